@@ -0,0 +1,251 @@
+use cpal::SampleRate;
+use std::time::Duration;
+
+const FREEVERB_SAMPLE_RATE: f32 = 44100.0;
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_GAIN: f32 = 3.0;
+
+fn scaled_length(tuning: usize, sample_rate: SampleRate) -> usize {
+    ((tuning as f32 * sample_rate.0 as f32 / FREEVERB_SAMPLE_RATE) as usize).max(1)
+}
+
+struct Comb {
+    buffer: Box<[f32]>,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Comb {
+            buffer: vec![0f32; length].into_boxed_slice(),
+            index: 0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.filter_store = 0.0;
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = output * (1.0 - damp) + self.filter_store * damp;
+        self.buffer[self.index] = input + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct AllPass {
+    buffer: Box<[f32]>,
+    index: usize,
+}
+
+impl AllPass {
+    fn new(length: usize) -> Self {
+        AllPass {
+            buffer: vec![0f32; length].into_boxed_slice(),
+            index: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Freeverb-style reverb: parallel damped combs into series allpasses. All delay lines are
+/// allocated up front, so processing never allocates.
+pub struct Reverb {
+    combs: Vec<Comb>,
+    allpasses: Vec<AllPass>,
+    /// 0 is fully dry, 1 is fully wet
+    pub mix: f32,
+    /// 0..=1, longer tails as it increases
+    pub roomsize: f32,
+    /// 0..=1, darker tails as it increases
+    pub damping: f32,
+    /// Whether the delay lines are known to hold only silence
+    silent: bool,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: SampleRate) -> Self {
+        Reverb {
+            combs: COMB_TUNINGS
+                .iter()
+                .map(|&tuning| Comb::new(scaled_length(tuning, sample_rate)))
+                .collect(),
+            allpasses: ALLPASS_TUNINGS
+                .iter()
+                .map(|&tuning| AllPass::new(scaled_length(tuning, sample_rate)))
+                .collect(),
+            mix: 0.0,
+            roomsize: 0.5,
+            damping: 0.5,
+            silent: true,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        if self.mix <= 0.0 {
+            // the delay lines stop while bypassed, so don't let them replay a stale tail later
+            if !self.silent {
+                for comb in self.combs.iter_mut() {
+                    comb.clear();
+                }
+                for allpass in self.allpasses.iter_mut() {
+                    allpass.clear();
+                }
+                self.silent = true;
+            }
+            return input;
+        }
+        self.silent = false;
+        let feedback = self.roomsize * 0.28 + 0.7;
+        let damp = self.damping * 0.4;
+        let scaled = input * REVERB_INPUT_GAIN;
+        let mut wet = 0f32;
+        for comb in self.combs.iter_mut() {
+            wet += comb.process(scaled, feedback, damp);
+        }
+        for allpass in self.allpasses.iter_mut() {
+            wet = allpass.process(wet);
+        }
+        input * (1.0 - self.mix) + wet * REVERB_WET_GAIN * self.mix
+    }
+}
+
+fn smoothing_coefficient(time: Duration, sample_rate: SampleRate) -> f32 {
+    if time.is_zero() {
+        0.0
+    } else {
+        (-1.0 / (time.as_secs_f32() * sample_rate.0 as f32)).exp()
+    }
+}
+
+/// Feed-forward peak compressor. The default ratio of 1 leaves the signal untouched.
+pub struct Compressor {
+    /// dBFS above which gain reduction starts
+    pub threshold: f32,
+    pub ratio: f32,
+    pub attack: Duration,
+    pub release: Duration,
+    sample_rate: SampleRate,
+    envelope: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: SampleRate) -> Self {
+        Compressor {
+            threshold: 0.0,
+            ratio: 1.0,
+            attack: Duration::from_millis(10),
+            release: Duration::from_millis(100),
+            sample_rate,
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let level = input.abs();
+        let time = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        let coefficient = smoothing_coefficient(time, self.sample_rate);
+        self.envelope = level + coefficient * (self.envelope - level);
+
+        let over = 20.0 * self.envelope.max(f32::MIN_POSITIVE).log10() - self.threshold;
+        if over <= 0.0 || self.ratio <= 1.0 {
+            return input;
+        }
+        let reduction = over * (1.0 - 1.0 / self.ratio);
+        input * f32::powf(10.0, -reduction / 20.0)
+    }
+}
+
+/// The master effects. The output applies these to each frame as it consumes it, when the frame
+/// is final, so that speculated frames which are discarded never pass through their state.
+pub struct MasterChain {
+    pub reverb: Reverb,
+    pub compressor: Compressor,
+    /// Linear gain applied last
+    pub gain: f32,
+}
+
+impl MasterChain {
+    pub fn new(sample_rate: SampleRate) -> Self {
+        MasterChain {
+            reverb: Reverb::new(sample_rate),
+            compressor: Compressor::new(sample_rate),
+            gain: 1.0,
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.compressor.process(self.reverb.process(sample)) * self.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: SampleRate = SampleRate(44100);
+
+    #[test]
+    fn reverb_impulse_leaves_a_decaying_tail() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.mix = 1.0;
+        reverb.process(1.0);
+        let tail: Vec<f32> = (0..SAMPLE_RATE.0 * 2)
+            .map(|_| reverb.process(0.0))
+            .collect();
+        let energy = |samples: &[f32]| samples.iter().map(|sample| sample * sample).sum::<f32>();
+        let early = energy(&tail[..SAMPLE_RATE.0 as usize / 2]);
+        let late = energy(&tail[SAMPLE_RATE.0 as usize * 3 / 2..]);
+        assert!(early > 0.0);
+        assert!(late < early);
+    }
+
+    #[test]
+    fn bypassed_reverb_does_not_replay_a_stale_tail() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.mix = 1.0;
+        reverb.process(1.0);
+        reverb.mix = 0.0;
+        reverb.process(0.0);
+        reverb.mix = 1.0;
+        assert!((0..SAMPLE_RATE.0).all(|_| reverb.process(0.0) == 0.0));
+    }
+
+    #[test]
+    fn compressor_applies_ratio_above_threshold() {
+        let mut compressor = Compressor::new(SAMPLE_RATE);
+        compressor.threshold = -20.0;
+        compressor.ratio = 4.0;
+        compressor.attack = Duration::from_millis(1);
+        // 0dBFS is 20dB over, so after the attack it should come out 15dB down
+        let settled = (0..SAMPLE_RATE.0 / 10)
+            .map(|_| compressor.process(1.0))
+            .last()
+            .unwrap();
+        assert!((settled - f32::powf(10.0, -15.0 / 20.0)).abs() < 1e-3);
+        // and a signal under the threshold is untouched once the envelope has released
+        compressor.release = Duration::ZERO;
+        assert_eq!(compressor.process(0.05), 0.05);
+    }
+}
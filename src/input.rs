@@ -1,15 +1,29 @@
 use vizia::prelude::*;
 use std::sync::mpsc;
 use std::collections::HashSet;
-use crate::JamEvent;
+use std::time::Duration;
+use crate::output;
+use crate::render::output_level;
+use crate::{JamEvent, JamParam};
 use crate::instrument::{InstrumentEvent, InstrumentParam, NoteEvent, NoteParam};
 
 #[derive(Lens)]
 pub struct VizData {
     pressed: HashSet<Code>,
+    /// The on-screen key the mouse is holding down, if any
+    mouse_key: Option<Code>,
+    level: f32,
+    /// The underrun count last reported
+    xruns: u64,
+    master_gain: f32,
+    reverb_mix: f32,
+    paused: bool,
     event_submission: mpsc::Sender<Option<JamEvent>>,
 }
 
+const SUSTAIN_PEDAL: Code = Code::Space;
+const PAUSE_TOGGLE: Code = Code::Pause;
+
 fn code_to_pitch_and_voice(code: Code) -> Option<(f32, u32)> {
     let note = match code {
         Code::KeyA => 0,
@@ -25,55 +39,89 @@ fn code_to_pitch_and_voice(code: Code) -> Option<(f32, u32)> {
     Some((440.0 * f32::powf(2.0, note as f32 / 12.0), note))
 }
 
+pub enum KeyboardEvent {
+    Press(Code),
+    /// Release whichever key the mouse is holding, wherever the mouse is now
+    Release,
+}
+
+enum MeterEvent {
+    Refresh,
+}
+
+enum ControlEvent {
+    SetMasterGain(f32),
+    SetReverbMix(f32),
+}
+
+const METER_REFRESH: Duration = Duration::from_millis(33);
+
+const KEYBOARD_ROWS: &[&[(Code, &str)]] = &[
+    &[
+        (Code::KeyQ, "Q"),
+        (Code::KeyW, "W"),
+        (Code::KeyE, "E"),
+        (Code::KeyR, "R"),
+        (Code::KeyT, "T"),
+        (Code::KeyY, "Y"),
+        (Code::KeyU, "U"),
+        (Code::KeyI, "I"),
+        (Code::KeyO, "O"),
+        (Code::KeyP, "P"),
+    ],
+    &[
+        (Code::KeyA, "A"),
+        (Code::KeyS, "S"),
+        (Code::KeyD, "D"),
+        (Code::KeyF, "F"),
+        (Code::KeyG, "G"),
+        (Code::KeyH, "H"),
+        (Code::KeyJ, "J"),
+        (Code::KeyK, "K"),
+        (Code::KeyL, "L"),
+        (Code::Semicolon, ";"),
+    ],
+    &[
+        (Code::KeyZ, "Z"),
+        (Code::KeyX, "X"),
+        (Code::KeyC, "C"),
+        (Code::KeyV, "V"),
+        (Code::KeyB, "B"),
+        (Code::KeyN, "N"),
+        (Code::KeyM, "M"),
+    ],
+];
+
+fn key_color(code: Code, pressed: &HashSet<Code>) -> Color {
+    if pressed.contains(&code) {
+        Color::rgb(240, 200, 80)
+    } else if code_to_pitch_and_voice(code).is_some() {
+        Color::rgb(110, 150, 210)
+    } else {
+        Color::rgb(70, 70, 70)
+    }
+}
+
 impl Model for VizData {
-    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
         event.map(|window_event, _| match window_event {
-            WindowEvent::KeyDown(code, _) => {
-                let code = *code;
-                if !self.pressed.insert(code) {
-                    return;
-                }
-                let Some((pitch, voice)) = code_to_pitch_and_voice(code) else {
-                    return;
-                };
-                self.event_submission
-                    .send(Some(JamEvent::InstrumentEvent {
-                        instrument: 0,
-                        event: InstrumentEvent::SetParam {
-                            param: InstrumentParam::NextNote(NoteParam::Pitch(pitch)),
-                        },
-                    }))
-                    .unwrap();
-                self.event_submission
-                    .send(Some(JamEvent::InstrumentEvent {
-                        instrument: 0,
-                        event: InstrumentEvent::NoteEvent {
-                            voice,
-                            event: NoteEvent::Hit {},
-                        },
-                    }))
-                    .unwrap();
-            }
-            WindowEvent::KeyUp(code, _) => {
-                let code = *code;
-                if !self.pressed.remove(&code) {
-                    return;
-                }
-                let Some((_, voice)) = code_to_pitch_and_voice(code) else {
-                    return;
-                };
-                self.event_submission
-                    .send(Some(JamEvent::InstrumentEvent {
-                        instrument: 0,
-                        event: InstrumentEvent::NoteEvent {
-                            voice,
-                            event: NoteEvent::Mute {},
-                        },
-                    }))
-                    .unwrap();
+            WindowEvent::KeyDown(Code::KeyQ, _) if cx.modifiers().contains(Modifiers::CTRL) => {
+                self.quit(cx);
             }
+            WindowEvent::KeyDown(code, _) => self.key_down(*code),
+            WindowEvent::KeyUp(code, _) => self.key_up(*code),
+            // we will never see the KeyUp for anything held while unfocused
+            WindowEvent::WindowFocused(false) => self.release_all(),
             _ => {}
         });
+        event.map(|keyboard_event, _| match keyboard_event {
+            KeyboardEvent::Press(code) => self.mouse_down(*code),
+            KeyboardEvent::Release => self.mouse_up(),
+        });
+        event.map(|meter_event, _| match meter_event {
+            MeterEvent::Refresh => self.refresh_meter(),
+        });
+        event.map(|control_event, _| self.control(control_event));
     }
 }
 
@@ -81,16 +129,269 @@ impl VizData {
     fn new(event_submission: mpsc::Sender<Option<JamEvent>>) -> Self {
         Self {
             pressed: HashSet::new(),
+            mouse_key: None,
+            level: 0.0,
+            xruns: 0,
+            // these match the defaults on the rendering side
+            master_gain: 1.0,
+            reverb_mix: 0.0,
+            paused: false,
             event_submission,
         }
     }
+
+    fn refresh_meter(&mut self) {
+        self.level = output_level();
+        // underruns are reported from here, since neither the audio nor the rendering thread can
+        // afford to wait on stderr
+        let xruns = output::xruns();
+        if xruns > self.xruns {
+            eprintln!("Warning: output underrun ({xruns} total)");
+            self.xruns = xruns;
+        }
+    }
+
+    fn key_down(&mut self, code: Code) {
+        if !self.pressed.insert(code) {
+            return;
+        }
+        if code == SUSTAIN_PEDAL {
+            self.set_sustain(true);
+            return;
+        }
+        if code == PAUSE_TOGGLE {
+            self.paused = !self.paused;
+            self.event_submission
+                .send(Some(JamEvent::SetPaused {
+                    paused: self.paused,
+                }))
+                .unwrap();
+            return;
+        }
+        let Some((pitch, voice)) = code_to_pitch_and_voice(code) else {
+            return;
+        };
+        // batched so that the renderer can't start a frame between setting the pitch and the hit
+        self.event_submission
+            .send(Some(JamEvent::Batch(vec![
+                JamEvent::InstrumentEvent {
+                    instrument: 0,
+                    event: InstrumentEvent::SetParam {
+                        param: InstrumentParam::NextNote(NoteParam::Pitch(pitch)),
+                    },
+                },
+                JamEvent::InstrumentEvent {
+                    instrument: 0,
+                    event: InstrumentEvent::NoteEvent {
+                        voice,
+                        event: NoteEvent::Hit {},
+                    },
+                },
+            ])))
+            .unwrap();
+    }
+
+    fn key_up(&mut self, code: Code) {
+        if !self.pressed.remove(&code) {
+            return;
+        }
+        if code == SUSTAIN_PEDAL {
+            self.set_sustain(false);
+            return;
+        }
+        let Some((_, voice)) = code_to_pitch_and_voice(code) else {
+            return;
+        };
+        self.event_submission
+            .send(Some(JamEvent::InstrumentEvent {
+                instrument: 0,
+                event: InstrumentEvent::NoteEvent {
+                    voice,
+                    event: NoteEvent::Mute {},
+                },
+            }))
+            .unwrap();
+    }
+
+    fn mouse_down(&mut self, code: Code) {
+        self.mouse_up();
+        self.mouse_key = Some(code);
+        self.key_down(code);
+    }
+
+    fn mouse_up(&mut self) {
+        if let Some(code) = self.mouse_key.take() {
+            self.key_up(code);
+        }
+    }
+
+    fn control(&mut self, event: &ControlEvent) {
+        let (name, value) = match *event {
+            ControlEvent::SetMasterGain(gain) => {
+                self.master_gain = gain;
+                ("master_gain", gain)
+            }
+            ControlEvent::SetReverbMix(mix) => {
+                self.reverb_mix = mix;
+                ("reverb_mix", mix)
+            }
+        };
+        self.event_submission
+            .send(Some(JamEvent::Param(JamParam::OtherFloat(
+                name.to_string(),
+                value,
+            ))))
+            .unwrap();
+    }
+
+    fn set_sustain(&mut self, sustain: bool) {
+        self.event_submission
+            .send(Some(JamEvent::InstrumentEvent {
+                instrument: 0,
+                event: InstrumentEvent::SetSustain { sustain },
+            }))
+            .unwrap();
+    }
+
+    fn release_all(&mut self) {
+        let pressed: Vec<Code> = self.pressed.iter().copied().collect();
+        for code in pressed {
+            self.key_up(code);
+        }
+        self.mouse_key = None;
+        debug_assert!(self.pressed.is_empty());
+    }
+
+    /// Tell the rendering thread to exit and close the window, which returns from `run()`
+    fn quit(&mut self, cx: &mut EventContext) {
+        self.release_all();
+        // a key-repeated Ctrl-Q can arrive after the rendering thread has already gone
+        self.event_submission.send(None).ok();
+        cx.emit(WindowEvent::WindowClose);
+    }
 }
 
 pub fn setup_input(event_submission: mpsc::Sender<Option<JamEvent>>) -> Application {
     Application::new(|cx| {
         VizData::new(event_submission).build(cx);
-        HStack::new(cx, |_| {})
-            .size(Pixels(50.0))
-            .lock_focus_to_within();
+        VStack::new(cx, |cx| {
+            for row in KEYBOARD_ROWS {
+                HStack::new(cx, |cx| {
+                    for &(code, label) in row.iter() {
+                        Label::new(cx, label)
+                            .size(Pixels(30.0))
+                            .child_space(Stretch(1.0))
+                            .background_color(
+                                VizData::pressed.map(move |pressed| key_color(code, pressed)),
+                            )
+                            .on_mouse_down(move |cx, _| {
+                                // keep the mouse until it is released, even off this key
+                                cx.capture();
+                                cx.emit(KeyboardEvent::Press(code));
+                            })
+                            .on_mouse_up(|cx, _| {
+                                cx.release();
+                                cx.emit(KeyboardEvent::Release);
+                            });
+                    }
+                })
+                .height(Auto)
+                .col_between(Pixels(2.0));
+            }
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Volume").width(Pixels(80.0));
+                Slider::new(cx, VizData::master_gain)
+                    .range(0.0..1.0)
+                    .on_changing(|cx, value| cx.emit(ControlEvent::SetMasterGain(value)));
+            })
+            .height(Pixels(24.0));
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Reverb").width(Pixels(80.0));
+                Slider::new(cx, VizData::reverb_mix)
+                    .range(0.0..1.0)
+                    .on_changing(|cx, value| cx.emit(ControlEvent::SetReverbMix(value)));
+            })
+            .height(Pixels(24.0));
+            Element::new(cx)
+                .height(Pixels(6.0))
+                .width(VizData::level.map(|level| Percentage(level.clamp(0.0, 1.0) * 100.0)))
+                .background_color(Color::rgb(80, 200, 120));
+        })
+        .row_between(Pixels(2.0))
+        .lock_focus_to_within();
+        let meter_timer = cx.add_timer(METER_REFRESH, None, |cx, action| {
+            if let TimerAction::Tick(_) = action {
+                cx.emit(MeterEvent::Refresh);
+            }
+        });
+        cx.start_timer(meter_timer);
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_all_releases_every_pressed_key() {
+        let (send, recv) = mpsc::channel();
+        let mut data = VizData::new(send);
+        data.key_down(Code::KeyA);
+        data.key_down(Code::KeyD);
+        data.key_down(SUSTAIN_PEDAL);
+        recv.try_iter().for_each(drop);
+
+        data.release_all();
+        assert!(data.pressed.is_empty());
+        let mut muted = vec![];
+        let mut sustain_released = false;
+        for event in recv.try_iter() {
+            match event {
+                Some(JamEvent::InstrumentEvent {
+                    event:
+                        InstrumentEvent::NoteEvent {
+                            voice,
+                            event: NoteEvent::Mute {},
+                        },
+                    ..
+                }) => muted.push(voice),
+                Some(JamEvent::InstrumentEvent {
+                    event: InstrumentEvent::SetSustain { sustain: false },
+                    ..
+                }) => sustain_released = true,
+                _ => panic!("unexpected event from release_all"),
+            }
+        }
+        muted.sort();
+        assert_eq!(muted, vec![0, 4]);
+        assert!(sustain_released);
+
+        // the same key can be pressed again afterwards
+        data.key_down(Code::KeyA);
+        assert!(matches!(recv.try_recv(), Ok(Some(JamEvent::Batch(_)))));
+    }
+
+    #[test]
+    fn sliders_send_their_params() {
+        let (send, recv) = mpsc::channel();
+        let mut data = VizData::new(send);
+        data.control(&ControlEvent::SetMasterGain(0.25));
+        data.control(&ControlEvent::SetReverbMix(0.75));
+        assert_eq!(data.master_gain, 0.25);
+        assert_eq!(data.reverb_mix, 0.75);
+        let sent: Vec<(String, f32)> = recv
+            .try_iter()
+            .map(|event| match event {
+                Some(JamEvent::Param(JamParam::OtherFloat(name, value))) => (name, value),
+                _ => panic!("unexpected event from a slider"),
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("master_gain".to_string(), 0.25),
+                ("reverb_mix".to_string(), 0.75)
+            ]
+        );
+    }
+}
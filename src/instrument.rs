@@ -1,10 +1,23 @@
 use std::time::Duration;
 
+const MAX_AMPLITUDE: f32 = 1.0;
+const DEFAULT_ARTICULATION: f32 = 0.5;
+/// The shortest release articulation can scale down to, so that staccato notes don't click
+const MIN_RELEASE: Duration = Duration::from_millis(5);
+
+fn cents_to_ratio(cents: f32) -> f32 {
+    f32::powf(2.0, cents / 1200.0)
+}
+
 #[non_exhaustive]
 pub enum NoteParam {
     Pitch(f32),
+    /// Linear gain multiplied into the envelope-scaled signal, clamped to 0..=MAX_AMPLITUDE
     Amplitude(f32),
+    /// 0..=1, scaling the release tail from staccato (0) through the default (0.5) to legato (1)
     Articulation(f32),
+    /// Offset from the pitch in cents
+    Detune(f32),
     OtherFloat(String, f32),
     OtherString(String, String),
 }
@@ -21,16 +34,61 @@ pub trait Note {
     fn mute(&mut self);
     fn render(&mut self, time: Duration) -> f32;
     fn finished(&mut self, time: Duration) -> bool;
+    /// Whether the note has started its release by `time`, whether or not it was muted
+    fn releasing(&self, _time: Duration) -> bool {
+        false
+    }
+    /// Restart the envelope without disturbing anything else, such as the oscillator phase
+    fn retrigger(&mut self) {}
 }
 
 pub trait Instrument: Send {
     fn set_param(&mut self, param: InstrumentParam);
     fn note(&mut self, voice: u32) -> Box<dyn Note>;
+    /// Whether a hit while a note is held should change that note rather than start another
+    fn mono(&self) -> bool {
+        false
+    }
+    /// Apply the next note's params to a note which is already sounding, for mono instruments
+    /// and for re-hits which keep their note
+    fn legato(&mut self, _note: &mut dyn Note) {}
+    /// How many notes repeated hits on one voice may keep ringing at once
+    fn voice_pool(&self) -> u32 {
+        1
+    }
+    /// What a hit does to a note already sounding on the same voice
+    fn retrigger(&self) -> Retrigger {
+        Retrigger::Restart
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Retrigger {
+    /// Replace the note with a fresh one
+    #[default]
+    Restart,
+    /// Keep the note and its phase, but start its envelope over
+    EnvelopeOnly,
+    /// Keep the note as it is, only applying the next note's params, if it is still held
+    Continue,
+}
+
+impl Retrigger {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "restart" => Some(Retrigger::Restart),
+            "envelope-only" => Some(Retrigger::EnvelopeOnly),
+            "continue" => Some(Retrigger::Continue),
+            _ => None,
+        }
+    }
 }
 
 pub enum InstrumentEvent {
     SetParam { param: InstrumentParam },
     NoteEvent { voice: u32, event: NoteEvent },
+    /// While sustained, mutes are deferred until the sustain is released
+    SetSustain { sustain: bool },
 }
 
 pub enum NoteEvent {
@@ -41,22 +99,309 @@ pub enum NoteEvent {
 
 pub struct HeldButtonInstrument {
     next_pitch: f32,
+    next_detune: f32,
+    next_duration: Option<Duration>,
     amplitude: f32,
+    articulation: f32,
+    glide: Duration,
+    tremolo: Tremolo,
+    adsr: Adsr,
+    highpass_cutoff: f32,
+    mono: bool,
+    voice_pool: u32,
+    amplitude_curve: AmplitudeCurve,
+    /// None passes pitches through unchanged
+    scale: Option<Scale>,
+    scale_root: f32,
+    retrigger: Retrigger,
 }
 
 impl HeldButtonInstrument {
     pub fn new() -> Self {
         Self {
             next_pitch: 440.0,
+            next_detune: 0.0,
+            next_duration: None,
             amplitude: 0.1,
+            articulation: DEFAULT_ARTICULATION,
+            glide: Duration::ZERO,
+            tremolo: Tremolo::default(),
+            adsr: Adsr::default(),
+            highpass_cutoff: 0.0,
+            mono: false,
+            voice_pool: 1,
+            amplitude_curve: AmplitudeCurve::Linear,
+            scale: None,
+            scale_root: 440.0,
+            retrigger: Retrigger::Restart,
         }
     }
+
+    fn new_note(&self) -> HeldButtonNote {
+        HeldButtonNote {
+            pitch: self.next_pitch,
+            detune: self.next_detune,
+            glide_from: self.next_pitch * cents_to_ratio(self.next_detune),
+            glide_start: Duration::ZERO,
+            glide: self.glide,
+            amplitude: self.amplitude_curve.gain(self.amplitude),
+            amplitude_curve: self.amplitude_curve,
+            articulation: self.articulation,
+            tremolo: self.tremolo,
+            adsr: self.adsr,
+            highpass: HighPass::new(self.highpass_cutoff),
+            mute_at: None,
+            duration: self.next_duration,
+            envelope_start: Duration::from_secs(0),
+            attack_from: 0.0,
+            change_phase: 0.0,
+            change_at: Duration::from_secs(0),
+            change_pending: None,
+        }
+    }
+}
+
+/// Decibels spanned by the exponential amplitude curve between a control of 0 and 1
+const AMPLITUDE_CURVE_RANGE_DB: f32 = 40.0;
+
+/// How an amplitude control in 0..=1 maps onto a linear gain
+#[derive(Clone, Copy, Default)]
+pub enum AmplitudeCurve {
+    #[default]
+    Linear,
+    /// Evenly spaced in decibels over AMPLITUDE_CURVE_RANGE_DB, so a control of 0.5 is -20dB (a
+    /// gain of 0.1). A control of 0 is still silent.
+    Exponential,
+}
+
+impl AmplitudeCurve {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(AmplitudeCurve::Linear),
+            "exp" | "exponential" => Some(AmplitudeCurve::Exponential),
+            _ => None,
+        }
+    }
+
+    fn gain(self, control: f32) -> f32 {
+        match self {
+            AmplitudeCurve::Linear => control,
+            AmplitudeCurve::Exponential if control <= 0.0 => 0.0,
+            AmplitudeCurve::Exponential => {
+                f32::powf(10.0, (control - 1.0) * AMPLITUDE_CURVE_RANGE_DB / 20.0)
+            }
+        }
+    }
+}
+
+/// A set of semitone offsets from a root which incoming pitches are snapped to
+#[derive(Clone, Copy)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    Pentatonic,
+}
+
+impl Scale {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chromatic" => Some(Scale::Chromatic),
+            "major" => Some(Scale::Major),
+            "minor" => Some(Scale::Minor),
+            "pentatonic" => Some(Scale::Pentatonic),
+            _ => None,
+        }
+    }
+
+    fn degrees(self) -> &'static [f32] {
+        match self {
+            Scale::Chromatic => &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0],
+            Scale::Major => &[0.0, 2.0, 4.0, 5.0, 7.0, 9.0, 11.0],
+            Scale::Minor => &[0.0, 2.0, 3.0, 5.0, 7.0, 8.0, 10.0],
+            Scale::Pentatonic => &[0.0, 2.0, 4.0, 7.0, 9.0],
+        }
+    }
+
+    /// The in-scale pitch nearest to `pitch` in semitones, in any octave of `root`
+    fn quantize(self, pitch: f32, root: f32) -> f32 {
+        if pitch <= 0.0 || root <= 0.0 {
+            return pitch;
+        }
+        let semitones = 12.0 * (pitch / root).log2();
+        let octave = (semitones / 12.0).floor();
+        let within = semitones - octave * 12.0;
+        // the root of the next octave up is a candidate too
+        let nearest = self
+            .degrees()
+            .iter()
+            .copied()
+            .chain(std::iter::once(12.0))
+            .min_by(|a, b| (a - within).abs().total_cmp(&(b - within).abs()))
+            .unwrap();
+        root * f32::powf(2.0, octave + nearest / 12.0)
+    }
+}
+
+/// One-pole RC high-pass. The coefficient is derived from the time elapsed since the previous
+/// frame, so it tracks whatever sample rate the note is rendered at. A cutoff of 0 bypasses it.
+struct HighPass {
+    cutoff: f32,
+    last: Option<(Duration, f32, f32)>,
+}
+
+impl HighPass {
+    fn new(cutoff: f32) -> Self {
+        Self { cutoff, last: None }
+    }
+
+    fn process(&mut self, time: Duration, input: f32) -> f32 {
+        if self.cutoff <= 0.0 {
+            return input;
+        }
+        let output = match self.last {
+            Some((last_time, last_input, last_output)) => {
+                let rc = 1.0 / (std::f32::consts::TAU * self.cutoff);
+                let dt = time.saturating_sub(last_time).as_secs_f32();
+                rc / (rc + dt) * (last_output + input - last_input)
+            }
+            None => input,
+        };
+        self.last = Some((time, input, output));
+        output
+    }
+}
+
+const EXPONENTIAL_STEEPNESS: f32 = 5.0;
+
+#[derive(Clone, Copy, Default)]
+pub enum CurveShape {
+    #[default]
+    Linear,
+    /// Moves quickly at the start of each stage and settles slowly, like an RC circuit
+    Exponential,
+}
+
+impl CurveShape {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(CurveShape::Linear),
+            "exp" | "exponential" => Some(CurveShape::Exponential),
+            _ => None,
+        }
+    }
+
+    /// Maps the normalized progress through an envelope stage onto 0..=1
+    fn shape(self, progress: f32) -> f32 {
+        match self {
+            CurveShape::Linear => progress,
+            CurveShape::Exponential => {
+                (1.0 - (-EXPONENTIAL_STEEPNESS * progress).exp())
+                    / (1.0 - (-EXPONENTIAL_STEEPNESS).exp())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Adsr {
+    attack: Duration,
+    decay: Duration,
+    sustain: f32,
+    release: Duration,
+    shape: CurveShape,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self {
+            attack: Duration::from_millis(50),
+            decay: Duration::from_millis(50),
+            sustain: 0.5,
+            release: Duration::from_millis(500),
+            shape: CurveShape::Linear,
+        }
+    }
+}
+
+fn stage_progress(elapsed: Duration, length: Duration) -> f32 {
+    if length.is_zero() {
+        1.0
+    } else {
+        (elapsed.as_secs_f32() / length.as_secs_f32()).min(1.0)
+    }
+}
+
+impl Adsr {
+    /// The release length after articulation is applied. The default articulation gives exactly
+    /// the configured release, and it scales linearly from there down to MIN_RELEASE.
+    fn release_length(&self, articulation: f32) -> Duration {
+        self.release.mul_f32(articulation / DEFAULT_ARTICULATION).max(MIN_RELEASE)
+    }
+
+    /// The level `time` after the envelope starts, with the attack rising from `attack_from`
+    fn level(
+        &self,
+        time: Duration,
+        mute_at: Option<Duration>,
+        articulation: f32,
+        attack_from: f32,
+    ) -> f32 {
+        match mute_at {
+            Some(release) if time >= release => {
+                let progress = stage_progress(time - release, self.release_length(articulation));
+                self.sustain * (1.0 - self.shape.shape(progress))
+            }
+            _ if time < self.attack => {
+                let progress = stage_progress(time, self.attack);
+                attack_from + (1.0 - attack_from) * self.shape.shape(progress)
+            }
+            _ if time < self.attack + self.decay => {
+                let progress = stage_progress(time - self.attack, self.decay);
+                1.0 + (self.sustain - 1.0) * self.shape.shape(progress)
+            }
+            _ => self.sustain,
+        }
+    }
+}
+
+/// Amplitude LFO applied after the envelope. A depth of 0 leaves the signal unchanged, and a depth
+/// of 1 swings the level all the way down to silence once per period.
+#[derive(Clone, Copy, Default)]
+struct Tremolo {
+    rate: f32,
+    depth: f32,
+}
+
+impl Tremolo {
+    fn gain(&self, time: Duration) -> f32 {
+        let lfo = 0.5 - 0.5 * (time.as_secs_f32() * self.rate * std::f32::consts::TAU).cos();
+        1.0 - self.depth * lfo
+    }
 }
 
 pub struct HeldButtonNote {
     pitch: f32,
+    detune: f32,
+    /// The frequency at glide_start, which ramps linearly to the target over the glide time
+    glide_from: f32,
+    glide_start: Duration,
+    glide: Duration,
+    /// The gain after amplitude_curve has been applied
     amplitude: f32,
+    amplitude_curve: AmplitudeCurve,
+    articulation: f32,
+    tremolo: Tremolo,
+    adsr: Adsr,
+    highpass: HighPass,
     mute_at: Option<Duration>,
+    /// Release automatically this long after the envelope starts, even without a mute
+    duration: Option<Duration>,
+    /// When the envelope was last (re)started
+    envelope_start: Duration,
+    /// The level the attack starts from, which is where the envelope was when it was retriggered
+    attack_from: f32,
+    /// The phase is change_phase at change_at, and integrates frequency() from there
     change_at: Duration,
     change_phase: f32,
     change_pending: Option<HeldButtonNoteChange>,
@@ -65,7 +410,13 @@ pub struct HeldButtonNote {
 #[derive(Default)]
 struct HeldButtonNoteChange {
     pitch: Option<f32>,
+    detune: Option<f32>,
     amplitude: Option<f32>,
+    articulation: Option<f32>,
+    glide: Option<Duration>,
+    tremolo_rate: Option<f32>,
+    tremolo_depth: Option<f32>,
+    retrigger: bool,
     mute: bool,
 }
 
@@ -76,8 +427,64 @@ impl HeldButtonNote {
         self.change_pending = Some(thing);
     }
 
+    /// When the release stage begins, if it has been scheduled at all
+    fn release_at(&self) -> Option<Duration> {
+        let duration_end = self.duration.map(|duration| self.envelope_start + duration);
+        match (self.mute_at, duration_end) {
+            (Some(mute_at), Some(duration_end)) => Some(mute_at.min(duration_end)),
+            (mute_at, duration_end) => mute_at.or(duration_end),
+        }
+    }
+
+    /// When the release stage reaches silence, if it has been scheduled at all
+    fn release_end(&self) -> Option<Duration> {
+        let release_length = self.adsr.release_length(self.articulation);
+        self.release_at().map(|release_at| release_at + release_length)
+    }
+
+    /// The frequency once any glide has finished
+    fn target_frequency(&self) -> f32 {
+        self.pitch * cents_to_ratio(self.detune)
+    }
+
+    fn gliding(&self, time: Duration) -> bool {
+        time.saturating_sub(self.glide_start) < self.glide
+    }
+
+    fn frequency(&self, time: Duration) -> f32 {
+        let elapsed = time.saturating_sub(self.glide_start);
+        let target = self.target_frequency();
+        if elapsed >= self.glide {
+            target
+        } else {
+            let progress = elapsed.as_secs_f32() / self.glide.as_secs_f32();
+            self.glide_from + (target - self.glide_from) * progress
+        }
+    }
+
+    /// The integral of frequency() from glide_start to time
+    fn glide_cycles(&self, time: Duration) -> f32 {
+        let elapsed = time.saturating_sub(self.glide_start).as_secs_f32();
+        let glide = self.glide.as_secs_f32();
+        let target = self.target_frequency();
+        if elapsed < glide {
+            self.glide_from * elapsed + (target - self.glide_from) * elapsed * elapsed / (2.0 * glide)
+        } else {
+            (self.glide_from + target) / 2.0 * glide + target * (elapsed - glide)
+        }
+    }
+
+    fn envelope(&self, time: Duration) -> f32 {
+        let envelope_time = time.saturating_sub(self.envelope_start);
+        let release_at = self
+            .release_at()
+            .map(|release_at| release_at.saturating_sub(self.envelope_start));
+        self.adsr.level(envelope_time, release_at, self.articulation, self.attack_from)
+    }
+
     fn phase(&self, time: Duration) -> f32 {
-        self.change_phase + (time - self.change_at).as_secs_f32() / (1.0 / self.pitch) * std::f32::consts::TAU
+        let cycles = self.glide_cycles(time) - self.glide_cycles(self.change_at);
+        self.change_phase + cycles * std::f32::consts::TAU
     }
 }
 
@@ -88,62 +495,111 @@ impl Note for HeldButtonNote {
                 NoteParam::Pitch(pitch) => {
                     change.pitch = Some(pitch);
                 },
+                NoteParam::Detune(cents) => {
+                    change.detune = Some(cents);
+                },
                 NoteParam::Amplitude(amp) => {
-                    change.amplitude = Some(amp);
+                    change.amplitude = Some(amp.clamp(0.0, MAX_AMPLITUDE));
+                },
+                NoteParam::Articulation(articulation) => {
+                    change.articulation = Some(articulation.clamp(0.0, 1.0));
+                },
+                NoteParam::OtherFloat(name, secs) if name == "glide" => {
+                    change.glide = Some(Duration::from_secs_f32(secs.max(0.0)));
+                },
+                NoteParam::OtherFloat(name, rate) if name == "tremolo_rate" => {
+                    change.tremolo_rate = Some(rate.max(0.0));
+                },
+                NoteParam::OtherFloat(name, depth) if name == "tremolo_depth" => {
+                    change.tremolo_depth = Some(depth.clamp(0.0, 1.0));
                 },
                 _ => {}
             }
         );
     }
 
+    // whichever of a mute and a retrigger comes last wins
     fn mute(&mut self) {
-        self.with_change(|change| change.mute = true);
+        self.with_change(|change| {
+            change.mute = true;
+            change.retrigger = false;
+        });
+    }
+
+    fn retrigger(&mut self) {
+        self.with_change(|change| {
+            change.retrigger = true;
+            change.mute = false;
+        });
     }
 
     fn render(&mut self, time: Duration) -> f32 {
         if let Some(change) = self.change_pending.take() {
             // convert the old change_at/change_phase into new time/phase
+            let frequency = self.frequency(time);
+            let level = self.envelope(time);
+            let was_gliding = self.gliding(time);
             self.change_phase = self.phase(time) % std::f32::consts::TAU;
             self.change_at = time;
+            // a new target starts a new glide from wherever the old one had got to, and other
+            // changes leave a glide in progress alone
+            if change.pitch.is_some()
+                || change.detune.is_some()
+                || change.glide.is_some()
+                || !was_gliding
+            {
+                self.glide_from = frequency;
+                self.glide_start = time;
+            }
+            if let Some(glide) = change.glide {
+                self.glide = glide;
+            }
+            if let Some(rate) = change.tremolo_rate {
+                self.tremolo.rate = rate;
+            }
+            if let Some(depth) = change.tremolo_depth {
+                self.tremolo.depth = depth;
+            }
             if let Some(pitch) = change.pitch {
                 self.pitch = pitch;
             }
+            if let Some(detune) = change.detune {
+                self.detune = detune;
+            }
             if let Some(amplitude) = change.amplitude {
-                self.amplitude = amplitude;
+                self.amplitude = self.amplitude_curve.gain(amplitude);
+            }
+            if let Some(articulation) = change.articulation {
+                self.articulation = articulation;
             }
-            if change.mute {
+            if change.retrigger {
+                // start the attack from the current level, so that the retrigger doesn't click
+                self.attack_from = level;
+                self.envelope_start = time;
+                self.mute_at = None;
+            }
+            // a note which is already releasing keeps its release, rather than starting it over
+            if change.mute && self.mute_at.is_none() {
                 self.mute_at = Some(time);
             }
         }
 
         let amp = self.phase(time).sin();
-        let adsr = if let Some(release) = self.mute_at {
-            if time >= release {
-                (1.0 - (time - release).as_secs_f32() / Duration::from_millis(500).as_secs_f32()) * 0.5
-            } else if time < Duration::from_millis(50) {
-                time.as_secs_f32() / Duration::from_millis(50).as_secs_f32()
-            } else if time < Duration::from_millis(100) {
-                (1.0 - (time - Duration::from_millis(50)).as_secs_f32() / Duration::from_millis(50).as_secs_f32()) * 0.5 + 0.5
-            } else {
-                0.5
-            }
-        } else {
-            if time < Duration::from_millis(50) {
-                time.as_secs_f32() / Duration::from_millis(50).as_secs_f32()
-            } else if time < Duration::from_millis(100) {
-                (1.0 - (time - Duration::from_millis(50)).as_secs_f32() / Duration::from_millis(50).as_secs_f32()) * 0.5 + 0.5
-            } else {
-                0.5
-            }
-        };
-        amp * adsr * self.amplitude
+        let value = amp * self.envelope(time) * self.tremolo.gain(time) * self.amplitude;
+        self.highpass.process(time, value)
     }
 
     fn finished(&mut self, time: Duration) -> bool {
-        if let Some(mute_at) = self.mute_at {
-            mute_at + Duration::from_millis(500) < time
-        } else {
-            false
+        // the release ramp reaches exactly zero at release_end
+        self.release_end().is_some_and(|release_end| release_end <= time)
+    }
+
+    fn releasing(&self, time: Duration) -> bool {
+        // a mute or retrigger which has not been rendered yet has not touched the envelope
+        match &self.change_pending {
+            Some(change) if change.mute => true,
+            Some(change) if change.retrigger => false,
+            _ => self.release_at().is_some_and(|release_at| release_at <= time),
         }
     }
 }
@@ -152,24 +608,346 @@ impl Instrument for HeldButtonInstrument {
     fn set_param(&mut self, param: InstrumentParam) {
         match param {
             InstrumentParam::NextNote(NoteParam::Pitch(pitch)) => {
-                self.next_pitch = pitch;
+                self.next_pitch = match self.scale {
+                    Some(scale) => scale.quantize(pitch, self.scale_root),
+                    None => pitch,
+                };
+            },
+            InstrumentParam::NextNote(NoteParam::Detune(cents)) => {
+                self.next_detune = cents;
+            },
+            InstrumentParam::NextNote(NoteParam::OtherFloat(name, secs)) if name == "duration" => {
+                // a non-positive duration means play until muted
+                self.next_duration = (secs > 0.0).then(|| Duration::from_secs_f32(secs));
+            },
+            InstrumentParam::NextNote(NoteParam::Amplitude(amp)) => {
+                self.amplitude = amp.clamp(0.0, MAX_AMPLITUDE);
+            },
+            InstrumentParam::NextNote(NoteParam::Articulation(articulation)) => {
+                self.articulation = articulation.clamp(0.0, 1.0);
+            },
+            InstrumentParam::OtherFloat(name, secs) if name == "release" => {
+                self.adsr.release = Duration::from_secs_f32(secs.max(0.0));
+            },
+            InstrumentParam::OtherFloat(name, secs) if name == "glide" => {
+                self.glide = Duration::from_secs_f32(secs.max(0.0));
+            },
+            InstrumentParam::OtherFloat(name, rate) if name == "tremolo_rate" => {
+                self.tremolo.rate = rate.max(0.0);
+            },
+            InstrumentParam::OtherFloat(name, depth) if name == "tremolo_depth" => {
+                self.tremolo.depth = depth.clamp(0.0, 1.0);
+            },
+            InstrumentParam::OtherFloat(name, cutoff) if name == "highpass_cutoff" => {
+                self.highpass_cutoff = cutoff.max(0.0);
+            },
+            InstrumentParam::OtherFloat(name, mono) if name == "mono" => {
+                self.mono = mono != 0.0;
+            },
+            InstrumentParam::OtherFloat(name, pool) if name == "voice_pool" => {
+                self.voice_pool = (pool as u32).max(1);
+            },
+            InstrumentParam::OtherString(name, curve) if name == "amplitude_curve" => {
+                match AmplitudeCurve::from_name(&curve) {
+                    Some(curve) => self.amplitude_curve = curve,
+                    None => eprintln!("Warning: unknown amplitude curve {curve}"),
+                }
+            },
+            InstrumentParam::OtherString(name, shape) if name == "envelope_curve" => {
+                match CurveShape::from_name(&shape) {
+                    Some(shape) => self.adsr.shape = shape,
+                    None => eprintln!("Warning: unknown envelope curve {shape}"),
+                }
+            },
+            InstrumentParam::OtherString(name, scale) if name == "scale" => {
+                if scale == "none" {
+                    self.scale = None;
+                } else {
+                    match Scale::from_name(&scale) {
+                        Some(scale) => self.scale = Some(scale),
+                        None => eprintln!("Warning: unknown scale {scale}"),
+                    }
+                }
+            },
+            InstrumentParam::OtherString(name, mode) if name == "retrigger" => {
+                match Retrigger::from_name(&mode) {
+                    Some(mode) => self.retrigger = mode,
+                    None => eprintln!("Warning: unknown retrigger mode {mode}"),
+                }
+            },
+            InstrumentParam::OtherFloat(name, root) if name == "scale_root" && root > 0.0 => {
+                self.scale_root = root;
             },
             _ => {}
         }
     }
 
     fn note(&mut self, _voice: u32) -> Box<dyn Note> {
-        Box::new(HeldButtonNote {
-            pitch: self.next_pitch,
-            amplitude: self.amplitude,
-            mute_at: None,
-            change_phase: 0.0,
-            change_at: Duration::from_secs(0),
-            change_pending: None,
-        })
+        Box::new(self.new_note())
+    }
+
+    fn mono(&self) -> bool {
+        self.mono
+    }
+
+    fn voice_pool(&self) -> u32 {
+        self.voice_pool
+    }
+
+    fn retrigger(&self) -> Retrigger {
+        self.retrigger
+    }
+
+    fn legato(&mut self, note: &mut dyn Note) {
+        note.set_param(NoteParam::Pitch(self.next_pitch));
+        note.set_param(NoteParam::Detune(self.next_detune));
     }
 }
 
 pub fn setup_instruments() -> Vec<Box<dyn Instrument>> {
     vec![Box::new(HeldButtonInstrument::new())]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glide_frequency_stays_between_endpoints() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("glide".to_string(), 1.0));
+        let mut note = instrument.new_note();
+        note.render(Duration::ZERO);
+        note.set_param(NoteParam::Pitch(880.0));
+        note.render(Duration::from_millis(100));
+        for ms in [200, 500, 900, 1099] {
+            let frequency = note.frequency(Duration::from_millis(ms));
+            assert!(frequency > 440.0 && frequency < 880.0, "{frequency}Hz at {ms}ms");
+        }
+        assert_eq!(note.frequency(Duration::from_millis(1100)), 880.0);
+    }
+
+    #[test]
+    fn other_changes_do_not_restart_a_glide() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("glide".to_string(), 1.0));
+        let mut note = instrument.new_note();
+        note.render(Duration::ZERO);
+        note.set_param(NoteParam::Pitch(880.0));
+        note.render(Duration::ZERO);
+        let halfway = note.frequency(Duration::from_millis(500));
+        note.set_param(NoteParam::Amplitude(0.5));
+        note.render(Duration::from_millis(500));
+        assert_eq!(note.frequency(Duration::from_millis(500)), halfway);
+        assert_eq!(note.frequency(Duration::from_secs(1)), 880.0);
+    }
+
+    #[test]
+    fn tremolo_period_follows_rate() {
+        let tremolo = Tremolo {
+            rate: 4.0,
+            depth: 1.0,
+        };
+        // the gain starts at full, dips to silence halfway through each period and comes back
+        for period in 0..3 {
+            let start = period as f32 * 0.25;
+            assert!((tremolo.gain(Duration::from_secs_f32(start)) - 1.0).abs() < 1e-4);
+            assert!(tremolo.gain(Duration::from_secs_f32(start + 0.125)).abs() < 1e-4);
+        }
+        let flat = Tremolo {
+            rate: 4.0,
+            depth: 0.0,
+        };
+        assert_eq!(flat.gain(Duration::from_secs_f32(0.125)), 1.0);
+    }
+
+    #[test]
+    fn exponential_decay_falls_faster_than_linear() {
+        let linear = Adsr::default();
+        let exponential = Adsr {
+            shape: CurveShape::Exponential,
+            ..Adsr::default()
+        };
+        let midpoint = linear.attack + linear.decay / 2;
+        let linear_level = linear.level(midpoint, None, DEFAULT_ARTICULATION, 0.0);
+        let exponential_level = exponential.level(midpoint, None, DEFAULT_ARTICULATION, 0.0);
+        assert!((linear_level - 0.75).abs() < 1e-6);
+        assert!(exponential_level < linear_level);
+        assert!(exponential_level > linear.sustain);
+    }
+
+    /// Energy in block averages, which keep mostly what is well below sample_rate / 64, and in
+    /// first differences, which keep mostly what is near the Nyquist frequency
+    fn band_energies(signal: &[f32]) -> (f32, f32) {
+        let low = signal
+            .chunks(64)
+            .map(|block| (block.iter().sum::<f32>() / block.len() as f32).powi(2))
+            .sum();
+        let high = signal.windows(2).map(|pair| (pair[1] - pair[0]).powi(2)).sum();
+        (low, high)
+    }
+
+    #[test]
+    fn highpass_attenuates_low_frequencies() {
+        const SAMPLE_RATE: f64 = 44100.0;
+        // xorshift, so that the noise is the same on every run
+        let mut state = 0x2545f491u32;
+        let noise: Vec<f32> = (0..44100)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 * 2.0 - 1.0
+            })
+            .collect();
+        let mut highpass = HighPass::new(2000.0);
+        let filtered: Vec<f32> = noise
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| {
+                highpass.process(Duration::from_secs_f64(i as f64 / SAMPLE_RATE), sample)
+            })
+            .collect();
+        let (noise_low, noise_high) = band_energies(&noise);
+        let (filtered_low, filtered_high) = band_energies(&filtered);
+        assert!(filtered_low / filtered_high < noise_low / noise_high * 0.25);
+    }
+
+    #[test]
+    fn finished_exactly_when_release_reaches_zero() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.adsr.release = Duration::from_millis(200);
+        let mut note = instrument.new_note();
+        note.mute();
+        note.render(Duration::from_secs(1));
+        let silent = note.release_end().unwrap();
+        assert!(silent.abs_diff(Duration::from_millis(1200)) < Duration::from_micros(1));
+        assert!(!note.finished(silent - Duration::from_micros(1)));
+        assert!(note.render(silent - Duration::from_millis(10)) != 0.0);
+        assert!(note.finished(silent));
+        assert_eq!(note.render(silent), 0.0);
+    }
+
+    #[test]
+    fn scale_snaps_to_the_nearest_degree() {
+        let semitones = |n: f32| 440.0 * f32::powf(2.0, n / 12.0);
+        let snapped = Scale::Major.quantize(semitones(3.3), 440.0);
+        assert!((snapped - semitones(4.0)).abs() < 0.01, "{snapped}Hz");
+        // just below the root is nearer the root than the seventh under it
+        let snapped = Scale::Major.quantize(semitones(-0.2), 440.0);
+        assert!((snapped - 440.0).abs() < 0.01, "{snapped}Hz");
+    }
+
+    #[test]
+    fn no_scale_passes_pitches_through() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherString(
+            "scale".to_string(),
+            "major".to_string(),
+        ));
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::Pitch(450.0)));
+        assert!((instrument.next_pitch - 440.0).abs() < 0.01);
+        instrument.set_param(InstrumentParam::OtherString(
+            "scale".to_string(),
+            "none".to_string(),
+        ));
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::Pitch(450.0)));
+        assert_eq!(instrument.next_pitch, 450.0);
+    }
+
+    #[test]
+    fn later_of_mute_and_retrigger_wins() {
+        let instrument = HeldButtonInstrument::new();
+        let mut note = instrument.new_note();
+        note.render(Duration::ZERO);
+        note.mute();
+        note.retrigger();
+        assert!(!note.releasing(Duration::from_millis(10)));
+        note.render(Duration::from_millis(10));
+        assert!(!note.releasing(Duration::from_millis(20)));
+        note.retrigger();
+        note.mute();
+        assert!(note.releasing(Duration::from_millis(20)));
+        note.render(Duration::from_millis(20));
+        assert!(note.releasing(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn retrigger_attacks_from_the_current_level() {
+        let instrument = HeldButtonInstrument::new();
+        let mut note = instrument.new_note();
+        note.render(Duration::ZERO);
+        let sustain = note.envelope(Duration::from_millis(200));
+        note.retrigger();
+        note.render(Duration::from_millis(200));
+        assert!((note.envelope(Duration::from_millis(200)) - sustain).abs() < 1e-6);
+        assert!(note.envelope(Duration::from_millis(225)) > sustain);
+        assert_eq!(note.envelope(Duration::from_millis(250)), 1.0);
+    }
+
+    #[test]
+    fn low_articulation_finishes_sooner() {
+        let mut instrument = HeldButtonInstrument::new();
+        let release_end = |instrument: &HeldButtonInstrument| {
+            let mut note = instrument.new_note();
+            note.render(Duration::ZERO);
+            note.mute();
+            note.render(Duration::from_millis(100));
+            note.release_end().unwrap()
+        };
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::Articulation(1.0)));
+        let legato = release_end(&instrument);
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::Articulation(0.2)));
+        let short = release_end(&instrument);
+        assert!(short < legato);
+        // even fully staccato notes get a short ramp rather than a hard cut
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::Articulation(0.0)));
+        let staccato = release_end(&instrument);
+        assert!(staccato < short);
+        assert_eq!(staccato, Duration::from_millis(100) + MIN_RELEASE);
+    }
+
+    #[test]
+    fn detune_offsets_the_frequency_in_cents() {
+        let mut instrument = HeldButtonInstrument::new();
+        let mut frequencies = vec![];
+        for cents in [-1200.0, -10.0, 0.0, 10.0, 1200.0] {
+            instrument.set_param(InstrumentParam::NextNote(NoteParam::Detune(cents)));
+            frequencies.push(instrument.new_note().target_frequency());
+        }
+        let expected = [220.0, 437.47, 440.0, 442.55, 880.0];
+        for (frequency, expected) in frequencies.iter().zip(expected) {
+            assert!((frequency - expected).abs() < 0.01, "{frequency}Hz, not {expected}Hz");
+        }
+    }
+
+    #[test]
+    fn exponential_amplitude_curve_puts_half_at_minus_20db() {
+        assert_eq!(AmplitudeCurve::Linear.gain(0.5), 0.5);
+        assert!((AmplitudeCurve::Exponential.gain(0.5) - 0.1).abs() < 1e-6);
+        assert_eq!(AmplitudeCurve::Exponential.gain(1.0), 1.0);
+        assert_eq!(AmplitudeCurve::Exponential.gain(0.0), 0.0);
+    }
+
+    #[test]
+    fn curve_params_accept_the_same_names() {
+        for name in ["linear", "exp", "exponential"] {
+            assert!(AmplitudeCurve::from_name(name).is_some(), "amplitude curve {name}");
+            assert!(CurveShape::from_name(name).is_some(), "envelope curve {name}");
+        }
+    }
+
+    #[test]
+    fn amplitude_scales_the_rendered_sample() {
+        let mut instrument = HeldButtonInstrument::new();
+        let mut render_at = |amplitude: f32| {
+            instrument.set_param(InstrumentParam::NextNote(NoteParam::Amplitude(amplitude)));
+            let mut note = instrument.new_note();
+            note.render(Duration::from_millis(101))
+        };
+        let quiet = render_at(0.2);
+        let loud = render_at(0.4);
+        assert!(quiet.abs() > 1e-3);
+        assert!((loud / quiet - 2.0).abs() < 1e-4, "{quiet} to {loud}");
+    }
+}
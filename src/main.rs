@@ -1,9 +1,8 @@
-use cpal::traits::StreamTrait;
-
 mod render;
 mod output;
 mod input;
 mod instrument;
+mod effects;
 
 #[non_exhaustive]
 pub enum JamParam {
@@ -17,13 +16,69 @@ pub enum JamEvent {
         instrument: u32,
         event: instrument::InstrumentEvent,
     },
+    Param(JamParam),
+    /// Install, replace, or (with None) remove the instrument at the given id
+    SetInstrument {
+        instrument: u32,
+        replacement: Option<Box<dyn instrument::Instrument>>,
+    },
+    /// Freeze or resume the render clock, holding every sounding note where it is
+    SetPaused {
+        paused: bool,
+    },
+    /// Apply all of the events at the same frame, in order
+    Batch(Vec<JamEvent>),
+    /// Apply the event exactly when rendering the given frame, counted like RenderQueue::tail_frame
+    Timed {
+        frame: u64,
+        event: Box<JamEvent>,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
-    let (stream, buf) = output::stream_setup_for()?;
+    let (output, buf) = output::stream_setup_for()?;
     let instruments = instrument::setup_instruments();
-    let event_submission = render::setup_rendering(buf, instruments);
-    stream.play()?;
+    let (event_submission, render_thread) = render::setup_rendering(buf, instruments);
+    let shutdown_submission = event_submission.clone();
+    output.play()?;
     input::setup_input(event_submission).run().unwrap();
+    shutdown(output, shutdown_submission, render_thread)
+}
+
+/// Stop the output, then tell the rendering thread to exit and wait for it
+fn shutdown(
+    output: output::Output,
+    event_submission: std::sync::mpsc::Sender<Option<JamEvent>>,
+    render_thread: std::thread::JoinHandle<()>,
+) -> anyhow::Result<()> {
+    output.pause()?;
+    drop(output);
+    // the renderer may already be gone if the window quit it
+    event_submission.send(None).ok();
+    render_thread
+        .join()
+        .map_err(|_| anyhow::Error::msg("Rendering thread panicked"))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    #[test]
+    fn shutdown_joins_the_rendering_thread() {
+        let buf = Arc::new(Mutex::new(render::RenderQueue::new(
+            cpal::SampleRate(44100),
+            Instant::now(),
+        )));
+        let output = output::Output::Null(output::NullSink::new(buf.clone()));
+        let (event_submission, render_thread) =
+            render::setup_rendering(buf.clone(), instrument::setup_instruments());
+        output.play().unwrap();
+        shutdown(output, event_submission, render_thread).unwrap();
+        // neither the output nor the rendering thread is holding the queue any more
+        assert_eq!(Arc::strong_count(&buf), 1);
+    }
+}
@@ -1,21 +1,60 @@
-use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{FromSample, SizedSample};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleRate, SizedSample};
 use std::time::{Instant, Duration};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use crate::render::RenderQueue;
 
 const MAX_BUFFER_CONSUME_SIZE: usize = 256; // this corresponds to a little more than 5ms at 44100Hz
 const BACKOFF_SLEEP: Duration = Duration::from_millis(1);
+const FADE_IN: Duration = Duration::from_millis(20);
+const NULL_SAMPLE_RATE: SampleRate = SampleRate(44100);
 
-pub fn stream_setup_for() -> Result<(cpal::Stream, Arc<Mutex<RenderQueue>>), anyhow::Error>
+static XRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of output callbacks which could not be filled from the render queue
+pub fn xruns() -> u64 {
+    XRUNS.load(Ordering::Relaxed)
+}
+
+/// Where the rendered frames go
+pub enum Output {
+    Stream(cpal::Stream),
+    /// Used when there is no output device
+    Null(NullSink),
+}
+
+impl Output {
+    pub fn play(&self) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Stream(stream) => stream.play()?,
+            Output::Null(sink) => sink.set_running(true),
+        }
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Stream(stream) => stream.pause()?,
+            Output::Null(sink) => sink.set_running(false),
+        }
+        Ok(())
+    }
+}
+
+pub fn stream_setup_for() -> Result<(Output, Arc<Mutex<RenderQueue>>), anyhow::Error>
 where
 {
-    let (_host, device, config) = host_device_setup()?;
+    let Some((_host, device, config)) = host_device_setup()? else {
+        eprintln!("Warning: Default output device is not available, output will be discarded");
+        let buf = Arc::new(Mutex::new(RenderQueue::new(NULL_SAMPLE_RATE, Instant::now())));
+        return Ok((Output::Null(NullSink::new(buf.clone())), buf));
+    };
     let fmt = config.sample_format();
     let mut config: cpal::StreamConfig = config.into();
     config.buffer_size = cpal::BufferSize::Fixed(MAX_BUFFER_CONSUME_SIZE as u32);
 
-    match fmt {
+    let (stream, buf) = match fmt {
         cpal::SampleFormat::I8 => make_stream::<i8>(&device, &config),
         cpal::SampleFormat::I16 => make_stream::<i16>(&device, &config),
         cpal::SampleFormat::I32 => make_stream::<i32>(&device, &config),
@@ -29,22 +68,24 @@ where
         sample_format => Err(anyhow::Error::msg(format!(
             "Unsupported sample format '{sample_format}'"
         ))),
-    }
+    }?;
+    Ok((Output::Stream(stream), buf))
 }
 
+/// The default output device and its config, or None if there is no default output device
 pub fn host_device_setup(
-) -> Result<(cpal::Host, cpal::Device, cpal::SupportedStreamConfig), anyhow::Error> {
+) -> Result<Option<(cpal::Host, cpal::Device, cpal::SupportedStreamConfig)>, anyhow::Error> {
     let host = cpal::default_host();
 
-    let device = host
-        .default_output_device()
-        .ok_or_else(|| anyhow::Error::msg("Default output device is not available"))?;
+    let Some(device) = host.default_output_device() else {
+        return Ok(None);
+    };
     println!("Output device : {}", device.name()?);
 
     let config = device.default_output_config()?;
     println!("Default output config : {:?}", config);
 
-    Ok((host, device, config))
+    Ok(Some((host, device, config)))
 }
 
 pub fn make_stream<T>(
@@ -55,6 +96,8 @@ where
     T: SizedSample + FromSample<f32>,
 {
     let num_channels = config.channels as usize;
+    // ramp up the first frames of the stream so that it does not start with a click
+    let fade_in_frames = ((config.sample_rate.0 as f32 * FADE_IN.as_secs_f32()) as u64).max(1);
 
     let buf = Arc::new(Mutex::new(RenderQueue::new(
         config.sample_rate,
@@ -81,21 +124,7 @@ where
                         break;
                     }
                 }
-                let mut buf = buf.lock().unwrap();
-
-                if buf.buffer.len() >= num_frames {
-                    for frame in output.chunks_mut(num_channels) {
-                        let rawval = buf.buffer.pop().unwrap();
-                        let value = T::from_sample(rawval);
-                        for sample in frame.iter_mut() {
-                            *sample = value;
-                        }
-                    }
-                    buf.last_consumed_size = num_frames as u64;
-                    buf.tail_frame += num_frames as u64;
-                } else {
-                    buf.last_consumed_size = 0;
-                }
+                consume(&mut buf.lock().unwrap(), output, num_channels, fade_in_frames);
             }
         },
         |err| {
@@ -107,3 +136,189 @@ where
     Ok((stream, buf))
 }
 
+
+/// Stands in for an output device by consuming frames on a timer, the way a device's callback
+/// would. The frames are thrown away, but tail_frame still advances, so the render clock and
+/// anything timed against it keep working.
+pub struct NullSink {
+    running: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl NullSink {
+    pub fn new(buf: Arc<Mutex<RenderQueue>>) -> Self {
+        let running = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let period = buf.lock().unwrap().sample_length() * MAX_BUFFER_CONSUME_SIZE as u32;
+        let thread = std::thread::Builder::new()
+            .name("null output".to_string())
+            .spawn({
+                let running = running.clone();
+                let stop = stop.clone();
+                move || {
+                    let mut output = [0f32; MAX_BUFFER_CONSUME_SIZE];
+                    let mut next = Instant::now();
+                    while !stop.load(Ordering::Relaxed) {
+                        if running.load(Ordering::Relaxed) {
+                            consume(&mut buf.lock().unwrap(), &mut output, 1, 1);
+                            next += period;
+                        } else {
+                            next = Instant::now() + period;
+                        }
+                        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                    }
+                }
+            })
+            .unwrap();
+        NullSink {
+            running,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    fn set_running(&self, running: bool) {
+        self.running.store(running, Ordering::Relaxed);
+    }
+}
+
+impl Drop for NullSink {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Fill `output` from the queue, or count an underrun if it doesn't have enough frames. The gain
+/// ramps up over the first `fade_in_frames` of the stream. This runs on the audio thread, so
+/// underruns are only counted here and the window reports them.
+fn consume<T>(buf: &mut RenderQueue, output: &mut [T], num_channels: usize, fade_in_frames: u64)
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let num_frames = output.len() / num_channels;
+    if buf.paused {
+        // not an underrun: the clock is deliberately held still
+        output.fill(T::EQUILIBRIUM);
+        buf.meter_silence(num_frames);
+        buf.last_consumed_size = 0;
+    } else if buf.buffer.len() >= num_frames {
+        for (i, frame) in output.chunks_mut(num_channels).enumerate() {
+            let rawval = buf.pop_frame().unwrap();
+            let frame_index = buf.tail_frame + i as u64;
+            let gain = (frame_index as f32 / fade_in_frames as f32).min(1.0);
+            let value = T::from_sample(rawval * gain);
+            for sample in frame.iter_mut() {
+                *sample = value;
+            }
+        }
+        buf.last_consumed_size = num_frames as u64;
+        buf.tail_frame += num_frames as u64;
+    } else {
+        buf.last_consumed_size = 0;
+        XRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held by the tests which count underruns, since the count is shared between them
+    static XRUNS_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn short_buffer_counts_an_underrun() {
+        let _xruns = XRUNS_LOCK.lock().unwrap();
+        let mut buf = RenderQueue::new(SampleRate(44100), Instant::now());
+        for _ in 0..100 {
+            buf.buffer.push(0.0);
+        }
+        let before = xruns();
+        let mut output = [0f32; 256];
+        consume(&mut buf, &mut output, 1, 1);
+        assert!(xruns() > before);
+        assert_eq!(buf.tail_frame, 0);
+        assert_eq!(buf.buffer.len(), 100);
+
+        let mut output = [0f32; 64];
+        consume(&mut buf, &mut output, 1, 1);
+        assert_eq!(buf.tail_frame, 64);
+        assert_eq!(buf.last_consumed_size, 64);
+    }
+
+    #[test]
+    fn first_frames_fade_in() {
+        let mut buf = RenderQueue::new(SampleRate(44100), Instant::now());
+        for _ in 0..16 {
+            buf.buffer.push(0.5);
+        }
+        // two channels, so each frame is written twice
+        let mut output = [0f32; 16];
+        consume(&mut buf, &mut output, 2, 4);
+        let gains: Vec<f32> = output
+            .chunks(2)
+            .map(|frame| {
+                assert_eq!(frame[0], frame[1]);
+                frame[0] / 0.5
+            })
+            .collect();
+        assert_eq!(gains, [0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0]);
+        // later buffers are past the ramp
+        let mut output = [0f32; 8];
+        consume(&mut buf, &mut output, 1, 4);
+        assert!(output.iter().all(|&sample| sample == 0.5));
+    }
+
+    #[test]
+    fn pause_holds_the_clock_still() {
+        let _xruns = XRUNS_LOCK.lock().unwrap();
+        let mut buf = RenderQueue::new(SampleRate(44100), Instant::now());
+        for _ in 0..100 {
+            buf.buffer.push(0.0);
+        }
+        let mut output = [0f32; 32];
+        consume(&mut buf, &mut output, 1, 1);
+        let paused_at = buf.tail_time();
+
+        buf.paused = true;
+        let before = xruns();
+        let mut output = [1f32; 64];
+        consume(&mut buf, &mut output, 1, 1);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert_eq!(xruns(), before);
+        assert_eq!(buf.tail_frame, 32);
+        assert_eq!(buf.buffer.len(), 68);
+        assert_eq!(buf.tail_time(), paused_at);
+
+        // resuming carries on from the frame it paused at
+        buf.paused = false;
+        let mut output = [0f32; 32];
+        consume(&mut buf, &mut output, 1, 1);
+        assert_eq!(buf.tail_frame, 64);
+        assert_eq!(buf.tail_time(), paused_at + buf.sample_length() * 32);
+    }
+
+    #[test]
+    fn null_sink_consumes_frames_and_advances_the_clock() {
+        let _xruns = XRUNS_LOCK.lock().unwrap();
+        let buf = Arc::new(Mutex::new(RenderQueue::new(
+            SampleRate(44100),
+            Instant::now(),
+        )));
+        for _ in 0..MAX_BUFFER_CONSUME_SIZE * 4 {
+            buf.lock().unwrap().buffer.push(0.0);
+        }
+        let sink = NullSink::new(buf.clone());
+        sink.set_running(true);
+        // about eight periods
+        std::thread::sleep(Duration::from_millis(50));
+        drop(sink);
+        let buf = buf.lock().unwrap();
+        assert_eq!(buf.tail_frame, MAX_BUFFER_CONSUME_SIZE as u64 * 4);
+        assert_eq!(buf.buffer.len(), 0);
+    }
+}
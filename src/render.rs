@@ -1,13 +1,47 @@
 use cpal::SampleRate;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
-use thread_priority::{ThreadBuilderExt, ThreadPriority};
+use thread_priority::{set_current_thread_priority, ThreadPriority, ThreadPriorityValue};
 
-use crate::instrument::{Instrument, InstrumentEvent, Note, NoteEvent};
-use crate::JamEvent;
+use crate::effects::MasterChain;
+use crate::instrument::{Instrument, InstrumentEvent, Note, NoteEvent, Retrigger};
+use crate::output;
+use crate::{JamEvent, JamParam};
 
 const MAX_BUFFER_SPECULATE_SIZE: usize = 1024;
+const MIN_BUFFER_SPECULATE_SIZE: usize = 512;
+/// How long to wait for events when the buffer is already full enough
+const FILL_WAIT: Duration = Duration::from_millis(1);
+const LEVEL_METER_FALLOFF: Duration = Duration::from_millis(300);
+
+/// The f32 bits of the decaying peak level of the mix
+static OUTPUT_LEVEL: AtomicU32 = AtomicU32::new(0);
+
+/// The recent peak absolute sample value of the mix, for metering
+pub fn output_level() -> f32 {
+    f32::from_bits(OUTPUT_LEVEL.load(Ordering::Relaxed))
+}
+
+struct LevelMeter {
+    peak: f32,
+    decay: f32,
+}
+
+impl LevelMeter {
+    fn new(sample_rate: SampleRate) -> Self {
+        LevelMeter {
+            peak: 0.0,
+            decay: (-1.0 / (LEVEL_METER_FALLOFF.as_secs_f32() * sample_rate.0 as f32)).exp(),
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.peak = sample.abs().max(self.peak * self.decay);
+        OUTPUT_LEVEL.store(self.peak.to_bits(), Ordering::Relaxed);
+    }
+}
 
 pub struct RenderQueue {
     pub buffer: dasp::ring_buffer::Bounded<Box<[f32]>>,
@@ -15,6 +49,10 @@ pub struct RenderQueue {
     pub last_consumed_size: u64,
     pub sample_rate: SampleRate,
     pub start_time: Instant,
+    /// While paused the output plays silence and tail_frame stops advancing
+    pub paused: bool,
+    pub master: MasterChain,
+    meter: LevelMeter,
 }
 
 impl RenderQueue {
@@ -29,6 +67,23 @@ impl RenderQueue {
             tail_frame: 0,
             sample_rate,
             start_time,
+            paused: false,
+            master: MasterChain::new(sample_rate),
+            meter: LevelMeter::new(sample_rate),
+        }
+    }
+
+    /// Take the frame at the tail of the buffer for output, through the master effects
+    pub fn pop_frame(&mut self) -> Option<f32> {
+        let sample = self.master.process(self.buffer.pop()?);
+        self.meter.push(sample);
+        Some(sample)
+    }
+
+    /// Let the meter fall off over frames of silence which didn't come from the buffer
+    pub fn meter_silence(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.meter.push(0.0);
         }
     }
 
@@ -43,6 +98,17 @@ impl RenderQueue {
             + self.sample_length().mul_f32((frame >> 32) as f32)
     }
 
+    /// The frame number at the head of the buffer, i.e. the insertion point
+    pub fn head_frame(&self) -> u64 {
+        self.tail_frame + self.buffer.len() as u64
+    }
+
+    /// Throw away every rendered frame not yet consumed, rewinding the head to the tail
+    pub fn discard_speculation(&mut self) {
+        assert!(self.buffer.drain().all(|_| true));
+        assert_eq!(self.buffer.len(), 0);
+    }
+
     /// The current timestamp at the head of the buffer, i.e. the insertion point
     pub fn head_time(&self) -> Instant {
         self.plus_sample_time(self.buffer.len() as u64)
@@ -54,94 +120,1046 @@ impl RenderQueue {
     }
 }
 
-pub fn setup_rendering(
-    buf: Arc<Mutex<RenderQueue>>,
-    mut instruments: Vec<Box<dyn Instrument>>,
-) -> mpsc::Sender<Option<JamEvent>> {
-    let (send, recv) = mpsc::channel();
+/// Global parameters owned by the rendering thread
+pub struct MixParams {
+    pub tempo: f32,
+    /// Only let the instrument that most recently received a hit through
+    pub auto_solo: bool,
+}
 
-    std::thread::Builder::new()
-        .name("rendering".to_string())
-        .spawn_with_priority(ThreadPriority::Max, move |result| {
-            if let Err(e) = result {
-                eprintln!("Warning: Could not set thread priority: {e}")
+impl MixParams {
+    pub fn new() -> Self {
+        MixParams {
+            tempo: 120.0,
+            auto_solo: false,
+        }
+    }
+
+    /// Apply a global parameter, including those of the master effects held by the RenderQueue
+    pub fn set_param(&mut self, param: JamParam, master: &mut MasterChain) {
+        match param {
+            JamParam::Tempo(tempo) => {
+                self.tempo = tempo;
             }
-            let mut voices = BTreeMap::<(u32, u32), (Instant, Box<dyn Note>)>::new();
-            loop {
-                for event in recv.try_iter() {
-                    let Some(event) = event else { return };
-                    let now = {
-                        let mut buf = buf.lock().unwrap();
-                        assert!(buf.buffer.drain().all(|_| true));
-                        assert_eq!(buf.buffer.len(), 0);
-                        buf.head_time()
-                    };
-                    match event {
-                        JamEvent::InstrumentEvent {
-                            instrument: iid,
-                            event,
-                        } => {
-                            let Some(instrument) = instruments.get_mut(iid as usize) else {
-                                eprintln!("Warning: event on nonexistent instrument");
-                                continue;
+            JamParam::OtherFloat(name, value) => match name.as_str() {
+                "auto_solo" => self.auto_solo = value != 0.0,
+                "master_gain" => master.gain = value.max(0.0),
+                "reverb_mix" => master.reverb.mix = value.clamp(0.0, 1.0),
+                "reverb_roomsize" => master.reverb.roomsize = value.clamp(0.0, 1.0),
+                "reverb_damping" => master.reverb.damping = value.clamp(0.0, 1.0),
+                "compressor_threshold" => master.compressor.threshold = value.min(0.0),
+                "compressor_ratio" => master.compressor.ratio = value.max(1.0),
+                "compressor_attack" => {
+                    master.compressor.attack = Duration::from_secs_f32(value.max(0.0))
+                }
+                "compressor_release" => {
+                    master.compressor.release = Duration::from_secs_f32(value.max(0.0))
+                }
+                _ => eprintln!("Warning: unknown parameter {name}"),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Priorities to request for the rendering thread, from most to least preferred
+fn priority_candidates() -> Vec<ThreadPriority> {
+    let mut candidates = vec![ThreadPriority::Max];
+    for value in [75u8, 50, 25] {
+        if let Ok(value) = ThreadPriorityValue::try_from(value) {
+            candidates.push(ThreadPriority::Crossplatform(value));
+        }
+    }
+    candidates
+}
+
+/// Set the current thread to the first candidate priority that `set` allows, returning it. `set`
+/// is normally set_current_thread_priority.
+fn raise_current_thread_priority<E: std::fmt::Display>(
+    candidates: &[ThreadPriority],
+    mut set: impl FnMut(ThreadPriority) -> Result<(), E>,
+) -> Option<ThreadPriority> {
+    candidates
+        .iter()
+        .copied()
+        .find(|&priority| match set(priority) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Warning: Could not set thread priority {priority:?}: {e}");
+                false
+            }
+        })
+}
+
+/// How far ahead of the output the render thread fills the buffer. This starts small to save
+/// work when events discard speculated frames, and grows whenever the output underruns.
+struct SpeculationTarget {
+    target: usize,
+    seen_xruns: u64,
+}
+
+impl SpeculationTarget {
+    fn new() -> Self {
+        SpeculationTarget {
+            target: MIN_BUFFER_SPECULATE_SIZE,
+            seen_xruns: output::xruns(),
+        }
+    }
+
+    /// The fill level to aim for, given the output's underrun count so far. The target only grows
+    /// a few times before reaching MAX_BUFFER_SPECULATE_SIZE, so this rarely logs.
+    fn update(&mut self, xruns: u64) -> usize {
+        if xruns > self.seen_xruns {
+            self.seen_xruns = xruns;
+            if self.target < MAX_BUFFER_SPECULATE_SIZE {
+                self.target = (self.target * 2).min(MAX_BUFFER_SPECULATE_SIZE);
+                eprintln!("Warning: now rendering {} frames ahead", self.target);
+            }
+        }
+        self.target
+    }
+}
+
+/// (instrument, voice, slot), where instruments with a voice pool spread repeated hits on one
+/// voice over several slots
+type VoiceKey = (u32, u32, u32);
+
+/// Sounding notes and the time they were hit
+type Voices = BTreeMap<VoiceKey, (Instant, Box<dyn Note>)>;
+
+fn instrument_keys(voices: &Voices, iid: u32) -> Vec<VoiceKey> {
+    voices
+        .range((iid, 0, 0)..=(iid, u32::MAX, u32::MAX))
+        .map(|(key, _)| *key)
+        .collect()
+}
+
+/// The slot most recently hit on the given voice
+fn current_key(slots: &HashMap<(u32, u32), u32>, iid: u32, voice: u32) -> VoiceKey {
+    (iid, voice, slots.get(&(iid, voice)).copied().unwrap_or(0))
+}
+
+/// Sum one frame of every voice at `now`, dropping the voices which had finished by `retired`. If
+/// `solo` is set, only that instrument's voices are audible, though the others still render.
+fn mix_frame(voices: &mut Voices, now: Instant, retired: Instant, solo: Option<u32>) -> f32 {
+    let mut result = 0f32;
+    voices.retain(|(iid, _, _), (ts, note)| {
+        if note.finished(retired.saturating_duration_since(*ts)) {
+            return false;
+        }
+        // keep rendering muted instruments so that they stay in time
+        let sample = note.render(now.saturating_duration_since(*ts));
+        if solo.is_none_or(|solo| solo == *iid) {
+            result += sample;
+        }
+        true
+    });
+    result
+}
+
+/// Everything owned by the rendering thread
+struct Renderer {
+    instruments: Vec<Option<Box<dyn Instrument>>>,
+    voices: Voices,
+    params: MixParams,
+    sustained: HashSet<u32>,
+    pending_release: BTreeSet<VoiceKey>,
+    /// Voices which have been hit and not yet muted. A note whose duration has run out is
+    /// releasing even while its key is here, so check Note::releasing too.
+    held: BTreeSet<VoiceKey>,
+    /// The slot of the latest hit on each (instrument, voice)
+    slots: HashMap<(u32, u32), u32>,
+    /// Voices whose note was made by an instrument which has since been replaced, so they are
+    /// left to ring out and never taken over by the replacement
+    orphaned: BTreeSet<VoiceKey>,
+    last_hit: Option<u32>,
+    /// Timed events waiting for the head of the buffer to reach their frame
+    scheduled: BTreeMap<u64, Vec<JamEvent>>,
+    /// The frame the latest timed event was applied at. Until the output consumes it, the
+    /// speculated frames can't be discarded, since the event could not be applied again.
+    last_timed_frame: Option<u64>,
+}
+
+impl Renderer {
+    fn new(instruments: Vec<Box<dyn Instrument>>) -> Self {
+        Renderer {
+            instruments: instruments.into_iter().map(Some).collect(),
+            voices: BTreeMap::new(),
+            params: MixParams::new(),
+            sustained: HashSet::new(),
+            pending_release: BTreeSet::new(),
+            held: BTreeSet::new(),
+            slots: HashMap::new(),
+            orphaned: BTreeSet::new(),
+            last_hit: None,
+            scheduled: BTreeMap::new(),
+            last_timed_frame: None,
+        }
+    }
+
+    /// Apply an event received from the channel. This usually discards the speculated frames so
+    /// that it takes effect as soon as the output can hear it.
+    fn receive(&mut self, buf: &mut RenderQueue, event: JamEvent) {
+        // timed events past the speculated frames don't invalidate them
+        let ahead = matches!(
+            event,
+            JamEvent::Timed { frame, .. } if frame >= buf.head_frame()
+        );
+        let pinned = self
+            .last_timed_frame
+            .is_some_and(|frame| frame >= buf.tail_frame);
+        if !ahead && !pinned {
+            buf.discard_speculation();
+        }
+        self.handle_event(buf, event);
+    }
+
+    /// Render the frame at the head of the buffer and push it
+    fn render_next(&mut self, buf: &mut RenderQueue) {
+        self.run_scheduled(buf);
+        let result = self.render_frame(buf.head_time(), buf.tail_time());
+        buf.buffer.push(result);
+    }
+
+    /// Mix the frame at `now`, before the master effects
+    fn render_frame(&mut self, now: Instant, retired: Instant) -> f32 {
+        let solo = if self.params.auto_solo {
+            self.last_hit
+        } else {
+            None
+        };
+        let count = self.voices.len();
+        let result = mix_frame(&mut self.voices, now, retired, solo);
+        if self.voices.len() < count {
+            // forget reaped notes, so that a later note on the same key isn't mistaken for held
+            let voices = &self.voices;
+            self.held.retain(|key| voices.contains_key(key));
+            self.pending_release.retain(|key| voices.contains_key(key));
+            self.orphaned.retain(|key| voices.contains_key(key));
+        }
+        result
+    }
+
+    /// Apply every scheduled event due at or before the head of the buffer
+    fn run_scheduled(&mut self, buf: &mut RenderQueue) {
+        while let Some(entry) = self.scheduled.first_entry() {
+            if *entry.key() > buf.head_frame() {
+                break;
+            }
+            self.last_timed_frame = Some(buf.head_frame());
+            for event in entry.remove() {
+                self.handle_event(buf, event);
+            }
+        }
+    }
+
+    /// Apply an event at the head of the buffer
+    fn handle_event(&mut self, buf: &mut RenderQueue, event: JamEvent) {
+        let now = buf.head_time();
+        match event {
+            JamEvent::InstrumentEvent {
+                instrument: iid,
+                event,
+            } => {
+                let Some(instrument) = self
+                    .instruments
+                    .get_mut(iid as usize)
+                    .and_then(Option::as_mut)
+                else {
+                    eprintln!("Warning: event on nonexistent instrument");
+                    return;
+                };
+                match event {
+                    InstrumentEvent::SetParam { param } => {
+                        instrument.set_param(param);
+                    }
+                    InstrumentEvent::SetSustain { sustain: true } => {
+                        self.sustained.insert(iid);
+                    }
+                    InstrumentEvent::SetSustain { sustain: false } => {
+                        self.sustained.remove(&iid);
+                        let released: Vec<_> = self
+                            .pending_release
+                            .range((iid, 0, 0)..=(iid, u32::MAX, u32::MAX))
+                            .copied()
+                            .collect();
+                        for key in released {
+                            self.pending_release.remove(&key);
+                            if let Some((_, note)) = self.voices.get_mut(&key) {
+                                note.mute();
+                            }
+                        }
+                    }
+                    InstrumentEvent::NoteEvent { voice, event } => match event {
+                        NoteEvent::Hit {} => {
+                            self.last_hit = Some(iid);
+                            let previous = current_key(&self.slots, iid, voice);
+                            if instrument.mono() {
+                                // a mono instrument only ever has one note at full length, which
+                                // moves to the new voice if it is still held or sustained
+                                let mut sounding = None;
+                                for key in instrument_keys(&self.voices, iid) {
+                                    let held = self.held.remove(&key);
+                                    let sustained = self.pending_release.remove(&key);
+                                    let (ts, note) = self.voices.get_mut(&key).unwrap();
+                                    let releasing =
+                                        note.releasing(now.saturating_duration_since(*ts));
+                                    if (held || sustained) && !releasing && sounding.is_none() {
+                                        sounding = self.voices.remove(&key);
+                                    } else if !releasing {
+                                        // anything else rings out where it is
+                                        note.mute();
+                                    }
+                                }
+                                self.slots.remove(&(iid, voice));
+                                if let Some((ts, mut note)) = sounding {
+                                    instrument.legato(note.as_mut());
+                                    self.held.insert((iid, voice, 0));
+                                    self.voices.insert((iid, voice, 0), (ts, note));
+                                    return;
+                                }
+                            }
+                            let pool = if instrument.mono() {
+                                1
+                            } else {
+                                instrument.voice_pool().max(1)
+                            };
+                            let key = (iid, voice, (previous.2 + 1) % pool);
+                            let existing = if key == previous && !self.orphaned.contains(&key) {
+                                self.voices.get_mut(&key)
+                            } else {
+                                None
                             };
-                            match event {
-                                InstrumentEvent::SetParam { param } => {
-                                    instrument.set_param(param);
+                            if let Some((ts, note)) = existing {
+                                let sounding = (self.held.contains(&key)
+                                    || self.pending_release.contains(&key))
+                                    && !note.releasing(now.saturating_duration_since(*ts));
+                                let keep = match instrument.retrigger() {
+                                    Retrigger::Restart => false,
+                                    Retrigger::EnvelopeOnly => {
+                                        note.retrigger();
+                                        true
+                                    }
+                                    Retrigger::Continue => sounding,
+                                };
+                                if keep {
+                                    // the note keeps its hit time, so its phase carries on
+                                    instrument.legato(note.as_mut());
+                                    self.pending_release.remove(&key);
+                                    self.held.insert(key);
+                                    return;
                                 }
-                                InstrumentEvent::NoteEvent { voice, event } => {
-                                    match event {
-                                        NoteEvent::Hit {} => {
-                                            let note = instrument.note(voice);
-                                            if let Some((_, mut oldnote)) =
-                                                voices.insert((iid, voice), (now, note))
-                                            {
-                                                // idk if necessary lol
-                                                oldnote.mute();
-                                            }
-                                        }
-                                        NoteEvent::SetParam { param } => {
-                                            let Some((_, note)) = voices.get_mut(&(iid, voice))
-                                            else {
-                                                eprintln!("Warning: event on nonexistent note");
-                                                continue;
-                                            };
-                                            note.set_param(param);
-                                        }
-                                        NoteEvent::Mute {} => {
-                                            let Some((_, note)) = voices.get_mut(&(iid, voice))
-                                            else {
-                                                eprintln!("Warning: event on nonexistent note");
-                                                continue;
-                                            };
-                                            note.mute();
-                                        }
+                            }
+                            if key != previous {
+                                // let the last hit on this voice ring out in its own slot
+                                self.held.remove(&previous);
+                                if let Some((_, note)) = self.voices.get_mut(&previous) {
+                                    if self.sustained.contains(&iid) {
+                                        self.pending_release.insert(previous);
+                                    } else {
+                                        note.mute();
                                     }
                                 }
                             }
+                            self.slots.insert((iid, voice), key.2);
+                            self.orphaned.remove(&key);
+                            self.pending_release.remove(&key);
+                            self.held.insert(key);
+                            let note = instrument.note(voice);
+                            if let Some((_, mut oldnote)) = self.voices.insert(key, (now, note)) {
+                                // idk if necessary lol
+                                oldnote.mute();
+                            }
+                        }
+                        NoteEvent::SetParam { param } => {
+                            let key = current_key(&self.slots, iid, voice);
+                            if self.orphaned.contains(&key) {
+                                eprintln!("Warning: event on note of a replaced instrument");
+                                return;
+                            }
+                            let Some((_, note)) = self.voices.get_mut(&key) else {
+                                eprintln!("Warning: event on nonexistent note");
+                                return;
+                            };
+                            note.set_param(param);
+                        }
+                        NoteEvent::Mute {} => {
+                            let key = current_key(&self.slots, iid, voice);
+                            self.held.remove(&key);
+                            if self.orphaned.contains(&key) {
+                                // already muted when its instrument was replaced
+                                return;
+                            }
+                            let Some((_, note)) = self.voices.get_mut(&key) else {
+                                // mono instruments move their note away from released voices
+                                if !instrument.mono() {
+                                    eprintln!("Warning: event on nonexistent note");
+                                }
+                                return;
+                            };
+                            if self.sustained.contains(&iid) {
+                                self.pending_release.insert(key);
+                            } else {
+                                note.mute();
+                            }
+                        }
+                    },
+                }
+            }
+            JamEvent::Param(param) => {
+                self.params.set_param(param, &mut buf.master);
+            }
+            JamEvent::SetInstrument {
+                instrument: iid,
+                replacement,
+            } => {
+                let slot = iid as usize;
+                if slot >= self.instruments.len() {
+                    self.instruments.resize_with(slot + 1, || None);
+                }
+                // a replaced instrument's notes ring out, a removed one's are dropped
+                for key in instrument_keys(&self.voices, iid) {
+                    self.pending_release.remove(&key);
+                    self.held.remove(&key);
+                    if replacement.is_some() {
+                        if let Some((ts, note)) = self.voices.get_mut(&key) {
+                            if !note.releasing(now.saturating_duration_since(*ts)) {
+                                note.mute();
+                            }
                         }
+                        self.orphaned.insert(key);
+                    } else {
+                        self.voices.remove(&key);
+                        self.orphaned.remove(&key);
                     }
                 }
+                self.sustained.remove(&iid);
+                self.slots.retain(|&(slot_iid, _), _| slot_iid != iid);
+                if replacement.is_none() && self.last_hit == Some(iid) {
+                    // otherwise auto-solo would keep everything else silent
+                    self.last_hit = None;
+                }
+                self.instruments[slot] = replacement;
+            }
+            JamEvent::Batch(events) => {
+                for event in events {
+                    self.handle_event(buf, event);
+                }
+            }
+            JamEvent::SetPaused { paused } => {
+                buf.paused = paused;
+            }
+            JamEvent::Timed { frame, event } => {
+                self.scheduled.entry(frame).or_default().push(*event);
+            }
+        }
+    }
+}
 
-                let mut buf = buf.lock().unwrap();
-                let now = buf.head_time();
-                let retired = buf.tail_time();
-                if buf.buffer.len() == buf.buffer.max_len() {
-                    continue;
+pub fn setup_rendering(
+    buf: Arc<Mutex<RenderQueue>>,
+    instruments: Vec<Box<dyn Instrument>>,
+) -> (mpsc::Sender<Option<JamEvent>>, std::thread::JoinHandle<()>) {
+    let (send, recv) = mpsc::channel();
+
+    let thread = std::thread::Builder::new()
+        .name("rendering".to_string())
+        .spawn(move || {
+            let candidates = priority_candidates();
+            match raise_current_thread_priority(&candidates, set_current_thread_priority) {
+                Some(priority) => println!("Rendering thread priority: {priority:?}"),
+                None => eprintln!("Warning: Rendering thread is running at normal priority"),
+            }
+            let mut renderer = Renderer::new(instruments);
+            let mut speculation = SpeculationTarget::new();
+            let mut received = None;
+            loop {
+                for event in received.take().into_iter().chain(recv.try_iter()) {
+                    let Some(event) = event else { return };
+                    renderer.receive(&mut buf.lock().unwrap(), event);
                 }
 
-                let mut result = 0f32;
-                voices.retain(|(_, _), (ts, note)| {
-                    if note.finished(retired - *ts) {
-                        return false;
+                let mut buf = buf.lock().unwrap();
+                if buf.buffer.len() >= speculation.update(output::xruns()) {
+                    // block until there is an event or the output has had time to consume frames
+                    drop(buf);
+                    match recv.recv_timeout(FILL_WAIT) {
+                        Ok(event) => received = Some(event),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
                     }
-                    result += note.render(now - *ts);
-                    true
-                });
-                buf.buffer.push(result);
+                    continue;
+                }
+                renderer.render_next(&mut buf);
             }
         })
         .unwrap();
 
-    send
+    (send, thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::{HeldButtonInstrument, InstrumentParam, NoteParam};
+
+    struct StubNote {
+        level: f32,
+        muted: bool,
+    }
+
+    impl Note for StubNote {
+        fn set_param(&mut self, param: NoteParam) {
+            if let NoteParam::Amplitude(level) = param {
+                self.level = level;
+            }
+        }
+
+        fn mute(&mut self) {
+            self.muted = true;
+        }
+
+        fn render(&mut self, _time: Duration) -> f32 {
+            self.level
+        }
+
+        fn finished(&mut self, _time: Duration) -> bool {
+            self.muted
+        }
+
+        fn releasing(&self, _time: Duration) -> bool {
+            self.muted
+        }
+    }
+
+    fn stub_note(level: f32) -> Box<dyn Note> {
+        Box::new(StubNote {
+            level,
+            muted: false,
+        })
+    }
+
+    /// Makes notes which sound at a constant level until they are muted
+    struct StubInstrument {
+        level: f32,
+        mono: bool,
+    }
+
+    impl Instrument for StubInstrument {
+        fn set_param(&mut self, param: InstrumentParam) {
+            if let InstrumentParam::NextNote(NoteParam::Amplitude(level)) = param {
+                self.level = level;
+            }
+        }
+
+        fn note(&mut self, _voice: u32) -> Box<dyn Note> {
+            stub_note(self.level)
+        }
+
+        fn mono(&self) -> bool {
+            self.mono
+        }
+
+        fn legato(&mut self, note: &mut dyn Note) {
+            note.set_param(NoteParam::Amplitude(self.level));
+        }
+    }
+
+    fn stub_instrument() -> Box<dyn Instrument> {
+        Box::new(StubInstrument {
+            level: 1.0,
+            mono: false,
+        })
+    }
+
+    fn queue() -> RenderQueue {
+        RenderQueue::new(SampleRate(1000), Instant::now())
+    }
+
+    fn note_event(instrument: u32, voice: u32, event: NoteEvent) -> JamEvent {
+        JamEvent::InstrumentEvent {
+            instrument,
+            event: InstrumentEvent::NoteEvent { voice, event },
+        }
+    }
+
+    fn hit(instrument: u32, voice: u32) -> JamEvent {
+        note_event(instrument, voice, NoteEvent::Hit {})
+    }
+
+    #[test]
+    fn sending_none_stops_the_rendering_thread() {
+        let buf = Arc::new(Mutex::new(queue()));
+        let (send, thread) = setup_rendering(buf, vec![stub_instrument()]);
+        send.send(Some(hit(0, 0))).unwrap();
+        send.send(None).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn removing_an_instrument_empties_its_voices() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument(), stub_instrument()]);
+        renderer.handle_event(&mut buf, hit(1, 0));
+        renderer.handle_event(&mut buf, hit(0, 0));
+        renderer.handle_event(&mut buf, hit(0, 2));
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::SetInstrument {
+                instrument: 0,
+                replacement: None,
+            },
+        );
+        assert!(instrument_keys(&renderer.voices, 0).is_empty());
+        assert_eq!(instrument_keys(&renderer.voices, 1).len(), 1);
+        assert_eq!(renderer.last_hit, None);
+        // and hits on the removed instrument go nowhere
+        renderer.handle_event(&mut buf, hit(0, 0));
+        assert!(instrument_keys(&renderer.voices, 0).is_empty());
+    }
+
+    #[test]
+    fn mono_instrument_moves_its_one_note() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(StubInstrument {
+            level: 1.0,
+            mono: true,
+        })]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::InstrumentEvent {
+                instrument: 0,
+                event: InstrumentEvent::SetParam {
+                    param: InstrumentParam::NextNote(NoteParam::Amplitude(0.5)),
+                },
+            },
+        );
+        renderer.handle_event(&mut buf, hit(0, 2));
+        assert_eq!(instrument_keys(&renderer.voices, 0), vec![(0, 2, 0)]);
+        renderer.render_next(&mut buf);
+        assert_eq!(buf.buffer.pop(), Some(0.5));
+    }
+
+    #[test]
+    fn mono_instrument_does_not_take_over_a_releasing_note() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("mono".to_string(), 1.0));
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::OtherFloat(
+            "duration".to_string(),
+            0.01,
+        )));
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(instrument)]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        // well past the duration, but not the release
+        for _ in 0..50 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(&mut buf, hit(0, 2));
+        // the first note rings out where it was, and the second starts afresh
+        assert_eq!(
+            instrument_keys(&renderer.voices, 0),
+            vec![(0, 0, 0), (0, 2, 0)]
+        );
+        assert_eq!(renderer.voices[&(0, 2, 0)].0, buf.head_time());
+    }
+
+    #[test]
+    fn timed_hit_starts_at_the_requested_frame() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument()]);
+        renderer.receive(
+            &mut buf,
+            JamEvent::Timed {
+                frame: 100,
+                event: Box::new(hit(0, 0)),
+            },
+        );
+        for _ in 0..200 {
+            renderer.render_next(&mut buf);
+        }
+        let frames: Vec<f32> = buf.buffer.drain().collect();
+        assert!(frames[..100].iter().all(|&frame| frame == 0.0));
+        assert!(frames[100..].iter().all(|&frame| frame == 1.0));
+    }
+
+    #[test]
+    fn untimed_events_keep_frames_with_timed_events_in_them() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument()]);
+        renderer.receive(
+            &mut buf,
+            JamEvent::Timed {
+                frame: 100,
+                event: Box::new(hit(0, 0)),
+            },
+        );
+        for _ in 0..200 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.receive(&mut buf, note_event(0, 0, NoteEvent::Mute {}));
+        assert_eq!(buf.head_frame(), 200);
+        // once the output has consumed the timed frame, speculation can be discarded again
+        for _ in 0..101 {
+            buf.buffer.pop();
+            buf.tail_frame += 1;
+        }
+        renderer.receive(&mut buf, hit(0, 2));
+        assert_eq!(buf.head_frame(), 101);
+    }
+
+    #[test]
+    fn thread_priority_falls_back_in_order() {
+        let candidates = priority_candidates();
+        let mut tried = vec![];
+        let chosen = raise_current_thread_priority(&candidates, |priority| {
+            tried.push(priority);
+            if tried.len() < 3 {
+                Err("not permitted")
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(tried, candidates[..3]);
+        assert_eq!(chosen, Some(candidates[2]));
+        // and when nothing is allowed, every candidate is tried before giving up
+        tried.clear();
+        let chosen = raise_current_thread_priority(&candidates, |priority| {
+            tried.push(priority);
+            Err("not permitted")
+        });
+        assert_eq!(tried, candidates);
+        assert_eq!(chosen, None);
+    }
+
+    #[test]
+    fn speculation_target_grows_on_new_underruns() {
+        let mut speculation = SpeculationTarget {
+            target: MIN_BUFFER_SPECULATE_SIZE,
+            seen_xruns: 3,
+        };
+        assert_eq!(speculation.update(3), MIN_BUFFER_SPECULATE_SIZE);
+        let grown = (MIN_BUFFER_SPECULATE_SIZE * 2).min(MAX_BUFFER_SPECULATE_SIZE);
+        assert_eq!(speculation.update(4), grown);
+        // the same underruns aren't counted twice
+        assert_eq!(speculation.update(4), grown);
+        for xruns in 5..20 {
+            speculation.update(xruns);
+        }
+        assert_eq!(speculation.update(20), MAX_BUFFER_SPECULATE_SIZE);
+    }
+
+    #[test]
+    fn level_meter_holds_the_peak() {
+        let mut meter = LevelMeter::new(SampleRate(44100));
+        meter.push(0.2);
+        meter.push(-0.9);
+        assert_eq!(meter.peak, 0.9);
+        meter.push(0.1);
+        assert!(meter.peak < 0.9 && meter.peak > 0.89);
+    }
+
+    #[test]
+    fn meter_falls_off_while_paused() {
+        let mut buf = queue();
+        buf.buffer.push(0.5);
+        buf.pop_frame();
+        let peak = buf.meter.peak;
+        buf.meter_silence(100);
+        assert!(buf.meter.peak < peak);
+    }
+
+    #[test]
+    fn mix_frame_sums_every_voice() {
+        let now = Instant::now();
+        let mut voices = Voices::new();
+        voices.insert((0, 0, 0), (now, stub_note(0.25)));
+        voices.insert((0, 1, 0), (now, stub_note(0.5)));
+        voices.insert((1, 0, 0), (now, stub_note(0.125)));
+        assert_eq!(mix_frame(&mut voices, now, now, None), 0.875);
+        // a soloed instrument is the only one heard, but the rest are kept
+        assert_eq!(mix_frame(&mut voices, now, now, Some(0)), 0.75);
+        assert_eq!(voices.len(), 3);
+    }
+
+    #[test]
+    fn mix_frame_reaps_finished_notes() {
+        let now = Instant::now();
+        let mut voices = Voices::new();
+        voices.insert((0, 0, 0), (now, stub_note(0.25)));
+        voices.insert((0, 1, 0), (now, stub_note(0.5)));
+        voices.get_mut(&(0, 1, 0)).unwrap().1.mute();
+        assert_eq!(mix_frame(&mut voices, now, now, None), 0.25);
+        assert_eq!(voices.keys().copied().collect::<Vec<_>>(), vec![(0, 0, 0)]);
+    }
+
+    #[test]
+    fn rapid_hits_fill_the_voice_pool_before_recycling() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("voice_pool".to_string(), 3.0));
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(instrument)]);
+        for _ in 0..3 {
+            renderer.handle_event(&mut buf, hit(0, 0));
+        }
+        assert_eq!(
+            instrument_keys(&renderer.voices, 0),
+            vec![(0, 0, 0), (0, 0, 1), (0, 0, 2)]
+        );
+        // the fourth hit takes over the oldest slot instead of adding one
+        renderer.handle_event(&mut buf, hit(0, 0));
+        assert_eq!(instrument_keys(&renderer.voices, 0).len(), 3);
+        assert_eq!(renderer.slots[&(0, 0)], 1);
+    }
+
+    fn continuing_instrument() -> Box<dyn Instrument> {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherString(
+            "retrigger".to_string(),
+            "continue".to_string(),
+        ));
+        Box::new(instrument)
+    }
+
+    #[test]
+    fn continue_rehit_keeps_the_hit_time() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![continuing_instrument()]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        let started = renderer.voices[&(0, 0, 0)].0;
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(&mut buf, hit(0, 0));
+        // the same note carries on, so its phase does too
+        assert_eq!(instrument_keys(&renderer.voices, 0), vec![(0, 0, 0)]);
+        assert_eq!(renderer.voices[&(0, 0, 0)].0, started);
+    }
+
+    #[test]
+    fn replacement_instrument_does_not_continue_the_old_note() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![continuing_instrument()]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::SetInstrument {
+                instrument: 0,
+                replacement: Some(continuing_instrument()),
+            },
+        );
+        renderer.handle_event(&mut buf, hit(0, 0));
+        assert_eq!(renderer.voices[&(0, 0, 0)].0, buf.head_time());
+        assert!(renderer.orphaned.is_empty());
+    }
+
+    /// The loudest a note gets over the 20ms from `from`, sampled finely enough to catch its peaks
+    fn peak_level(note: &mut dyn Note, from: Duration) -> f32 {
+        (0..400)
+            .map(|i| note.render(from + Duration::from_micros(50 * i)).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn rehit_does_not_restart_a_releasing_slot() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("voice_pool".to_string(), 3.0));
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(instrument)]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        let key = (0, 0, 1);
+        let started = renderer.voices[&key].0;
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(&mut buf, note_event(0, 0, NoteEvent::Mute {}));
+        for _ in 0..400 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let before = peak_level(note, since_hit - Duration::from_millis(20));
+        renderer.handle_event(&mut buf, hit(0, 0));
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let after = peak_level(note, since_hit);
+        assert!(after <= before, "the tail rose from {before} to {after}");
+    }
+
+    #[test]
+    fn mono_hit_does_not_restart_earlier_tails() {
+        let mut instrument = HeldButtonInstrument::new();
+        instrument.set_param(InstrumentParam::OtherFloat("mono".to_string(), 1.0));
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(instrument)]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        let key = (0, 0, 0);
+        let started = renderer.voices[&key].0;
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(&mut buf, note_event(0, 0, NoteEvent::Mute {}));
+        for _ in 0..400 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let before = peak_level(note, since_hit - Duration::from_millis(20));
+        renderer.handle_event(&mut buf, hit(0, 2));
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let after = peak_level(note, since_hit);
+        assert!(after <= before, "the tail rose from {before} to {after}");
+    }
+
+    #[test]
+    fn replacing_an_instrument_does_not_restart_its_tails() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(HeldButtonInstrument::new())]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        let key = (0, 0, 0);
+        let started = renderer.voices[&key].0;
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        renderer.handle_event(&mut buf, note_event(0, 0, NoteEvent::Mute {}));
+        for _ in 0..400 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let before = peak_level(note, since_hit - Duration::from_millis(20));
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::SetInstrument {
+                instrument: 0,
+                replacement: Some(Box::new(HeldButtonInstrument::new())),
+            },
+        );
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        let since_hit = buf.head_time() - started;
+        let note = renderer.voices.get_mut(&key).unwrap().1.as_mut();
+        let after = peak_level(note, since_hit);
+        assert!(after <= before, "the tail rose from {before} to {after}");
+    }
+
+    #[test]
+    fn tempo_param_updates_the_render_side_tempo() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument()]);
+        renderer.handle_event(&mut buf, JamEvent::Param(JamParam::Tempo(90.0)));
+        assert_eq!(renderer.params.tempo, 90.0);
+    }
+
+    #[test]
+    fn batched_events_apply_together_at_one_frame() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument()]);
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        let at = buf.head_time();
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::Batch(vec![
+                JamEvent::InstrumentEvent {
+                    instrument: 0,
+                    event: InstrumentEvent::SetParam {
+                        param: InstrumentParam::NextNote(NoteParam::Amplitude(0.5)),
+                    },
+                },
+                hit(0, 0),
+            ]),
+        );
+        assert_eq!(renderer.voices[&(0, 0, 0)].0, at);
+        renderer.render_next(&mut buf);
+        assert_eq!(buf.buffer.drain().last(), Some(0.5));
+    }
+
+    fn sustain(instrument: u32, sustain: bool) -> JamEvent {
+        JamEvent::InstrumentEvent {
+            instrument,
+            event: InstrumentEvent::SetSustain { sustain },
+        }
+    }
+
+    #[test]
+    fn sustain_holds_released_notes_until_it_is_cleared() {
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![stub_instrument()]);
+        renderer.handle_event(&mut buf, sustain(0, true));
+        renderer.handle_event(&mut buf, hit(0, 0));
+        renderer.handle_event(&mut buf, note_event(0, 0, NoteEvent::Mute {}));
+        for _ in 0..10 {
+            renderer.render_next(&mut buf);
+        }
+        assert!(buf.buffer.drain().all(|frame| frame == 1.0));
+        assert!(renderer.pending_release.contains(&(0, 0, 0)));
+        renderer.handle_event(&mut buf, sustain(0, false));
+        renderer.render_next(&mut buf);
+        assert!(renderer.voices.is_empty());
+        assert!(renderer.pending_release.is_empty());
+    }
+
+    #[test]
+    fn duration_limited_note_is_reaped_when_its_release_ends() {
+        let mut instrument = HeldButtonInstrument::new();
+        // 125ms, then the default 500ms release
+        instrument.set_param(InstrumentParam::NextNote(NoteParam::OtherFloat(
+            "duration".to_string(),
+            0.125,
+        )));
+        let mut buf = queue();
+        let mut renderer = Renderer::new(vec![Box::new(instrument)]);
+        renderer.handle_event(&mut buf, hit(0, 0));
+        for frame in 0..=625 {
+            assert_eq!(renderer.voices.len(), 1, "reaped early at frame {frame}");
+            // consume as we go, since notes are only reaped once the output is past their end
+            renderer.render_next(&mut buf);
+            buf.pop_frame();
+            buf.tail_frame += 1;
+        }
+        assert!(renderer.voices.is_empty());
+        assert!(renderer.held.is_empty());
+    }
+
+    #[test]
+    fn auto_solo_follows_the_last_hit_instrument() {
+        let mut buf = queue();
+        let quiet = Box::new(StubInstrument {
+            level: 0.5,
+            mono: false,
+        });
+        let mut renderer = Renderer::new(vec![stub_instrument(), quiet]);
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::Param(JamParam::OtherFloat("auto_solo".to_string(), 1.0)),
+        );
+        renderer.handle_event(&mut buf, hit(0, 0));
+        renderer.handle_event(&mut buf, hit(1, 0));
+        renderer.render_next(&mut buf);
+        assert_eq!(buf.buffer.pop(), Some(0.5));
+        renderer.handle_event(&mut buf, hit(0, 2));
+        renderer.render_next(&mut buf);
+        assert_eq!(buf.buffer.pop(), Some(2.0));
+        // both instruments are heard again once auto-solo is off
+        renderer.handle_event(
+            &mut buf,
+            JamEvent::Param(JamParam::OtherFloat("auto_solo".to_string(), 0.0)),
+        );
+        renderer.render_next(&mut buf);
+        assert_eq!(buf.buffer.pop(), Some(2.5));
+    }
 }